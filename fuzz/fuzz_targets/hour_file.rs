@@ -0,0 +1,29 @@
+//! Feeds arbitrary bytes as an hour file to the cursor.
+//!
+//! Run with `cargo +nightly fuzz run hour_file` from the repository root.
+#![no_main]
+use std::io::ErrorKind;
+use chrono::prelude::*;
+use libfuzzer_sys::fuzz_target;
+use local_storage::database::CorruptFile;
+use local_storage::Database;
+
+fuzz_target!(|data: &[u8]| {
+    let database = Database::in_memory();
+    let hour = Utc.ymd(2020, 1, 1).and_hms(0, 0, 0);
+    database.storage().append("fuzz", &hour, data).unwrap();
+
+    // Every record takes at least 4 bytes, then one error for the file and the end
+    let mut cursor = database.get_data("fuzz", 0, u32::MAX).unwrap();
+    for _ in 0..data.len() / 4 + 2 {
+        match cursor.try_next() {
+            Ok(Some(record)) => assert_eq!(record.checksum, crc::crc32::checksum_ieee(&record.datalog)),
+            Ok(None) => return,
+            Err(error) => {
+                assert_eq!(error.kind(), ErrorKind::InvalidData);
+                assert!(error.get_ref().unwrap().downcast_ref::<CorruptFile>().is_some());
+            }
+        }
+    }
+    panic!("Cursor didn't finish reading {} bytes", data.len());
+});
@@ -1,45 +1,144 @@
 extern crate chrono;
 
 use std::io;
-use std::io::prelude::*;
-use std::io::Cursor;
 use std::fs;
 use std::fs::File;
 use std::fs::create_dir_all;
 use std::fs::OpenOptions;
-use std::fs::remove_file;
-use std::path::Path;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
 use std::io::{Error, ErrorKind};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, LazyLock, Mutex, RwLock, Weak};
+use fs2::FileExt;
 use chrono::prelude::*;
 use chrono::Duration;
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 use crc::crc32;
-use rmps::{Serializer, Deserializer};
-use rmps::decode::ReadReader;
+use rmps::Serializer;
 use log::{error, info};
 
-static DATE_FORMAT: &str = "%Y%m%d";
-static TIME_FORMAT: &str = "%H";
+use crate::query::{Aggregator, Filter};
+use crate::storage::{self, FsStorage, MemoryStorage, Storage};
+use crate::table::Table;
 
-#[derive(Debug)]
+pub static DATE_FORMAT: &str = "%Y%m%d";
+pub static TIME_FORMAT: &str = "%H";
+static LOCK_FILE: &str = ".lock";
+static MAX_NAME_LENGTH: usize = 64;
+
+/// Records nest at most an array (the datalog) in a record, anything
+/// nested deeper is corrupt
+static MAX_RECORD_DEPTH: usize = 8;
+
+/// Every source opened by this process, so handles to the same
+/// directory share their table locks and the writer lock file
+static OPEN_SOURCES: LazyLock<Mutex<HashMap<PathBuf, Weak<SourceState>>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Database
+///
+/// Handles to the same database can be cloned and shared between
+/// threads (or wrapped in an `Arc`). Every table has its own lock so
+/// any number of readers can read a table while a single writer appends to it.
+///
+/// A writable handle also holds an advisory lock on `<source>/.lock`
+/// so only one process at a time can write to a source.
+///
+/// Records are kept by a `Storage`, the file system unless the database
+/// was created with `Database::in_memory()` or `Database::with_storage()`.
+#[derive(Debug, Clone)]
 pub struct Database {
-    pub source:         &'static str,
+    pub source:         PathBuf,    // Directory of the database, empty if it isn't on the file system
+    read_only:          bool,
+    state:              Arc<SourceState>,
+    storage:            Arc<dyn Storage>,
+}
+
+/// SourceState
+///
+/// State shared by every handle to the same source within this process
+#[derive(Debug, Default)]
+struct SourceState {
+    tables:     Mutex<HashMap<String, Arc<RwLock<()>>>>,
+    lock_file:  Mutex<Option<File>>,
+}
+
+/// InvalidName
+///
+/// Error for a table name or path that is not allowed, e.g. because it
+/// could resolve outside of the database source. It is returned inside an
+/// `io::Error` of kind `InvalidInput`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidName {
+    pub name:       String,
+    pub reason:     &'static str,
+}
+
+impl fmt::Display for InvalidName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid name {:?}: {}", self.name, self.reason)
+    }
+}
+
+impl std::error::Error for InvalidName {}
+
+/// CorruptFile
+///
+/// Error for bytes of an hour file that aren't a valid record, e.g. after
+/// a partial write or a flash failure. It is returned inside an `io::Error`
+/// of kind `InvalidData`, once the valid records of the file were read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorruptFile {
+    pub table:      String,
+    pub hour:       DateTime<Utc>,
+    pub offset:     usize,      // Byte offset of the first corrupt record
+    pub reason:     String,
 }
 
+impl fmt::Display for CorruptFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Corrupt hour file {}/{}/{} at byte {}: {}", self.table, self.hour.format(DATE_FORMAT), self.hour.format(TIME_FORMAT), self.offset, self.reason)
+    }
+}
+
+impl std::error::Error for CorruptFile {}
+
 #[derive(Debug)]
 pub struct Entry {
-    pub table: &'static str,
+    pub table:      String,
     pub data:       Vec<u8>,
 }
 
+/// Continuation
+///
+/// Where a cursor stopped: the hour file, the byte offset in it of the last
+/// record returned and that record's id. Records are returned in (id, offset)
+/// order, so a cursor resumed from it returns exactly the records after that
+/// one, even if more were appended to the hour file meanwhile.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Continuation {
+    pub table:      String,
+    pub shard:      u32,        // Start of the hour file
+    pub offset:     u32,        // Byte offset of the last record in the hour file
+    pub last_id:    u32,
+}
+
+/// Records of an hour file with their byte offsets, in id order
+pub type FileRecords = VecDeque<(usize, MpdRecordType)>;
+
 #[derive(Debug)]
 pub struct MyCursor {
     pub database:       Database,
-    pub table:          &'static str,
-    pub de:             Deserializer<ReadReader<Cursor<Vec<u8>>>>,
+    pub table:          String,
+    pub records:        FileRecords,              // Records of the current file
+    pub corrupt:        Option<CorruptFile>,      // Corruption of the current file, reported after its records
     pub curr_ts:        DateTime<Utc>,
     pub start_ts:       u32,
     pub end_ts:         u32,
+    pub after:          Option<Continuation>,     // Skip records up to this one
+    pub position:       Option<(DateTime<Utc>, usize, u32)>,   // Hour file, offset and id of the last record returned
+    pub filter:         Option<Filter>,           // Only return records whose datalog matches
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -49,64 +148,118 @@ pub struct MpdRecordType {
     pub checksum:   u32,        // CRC-32 checksum of 'datalog'
 }
 
-pub trait DB {
-    // Set a new source for the database
-    fn set_source(&self, source: &str) -> Result<(), io::Error>;
-    
-    // Lists all the databases within the current data source
-    fn list_db(&self);
-
-    // Insert into database
-    fn insert(&self, entry: Entry) -> Result<(), io::Error>;
-
-    // Find a particular file
-    fn find_file(&self, source: &str) -> Result<Vec<u8>, io::Error>;
-
-    // Find a partical Entry
-    fn find_data(&self, date: &str);
+impl MpdRecordType {
+    /// Constructor
+    ///
+    /// Creates a record and computes the checksum of its datalog
+    pub fn new(id: u32, datalog: Vec<u8>) -> MpdRecordType {
+        let checksum = crc32::checksum_ieee(&datalog);
+        MpdRecordType {
+            id,
+            datalog,
+            checksum
+        }
+    }
 }
 
 impl MyCursor {
     // Constructor
-    pub fn new(db: Database, tb: &'static str, deserializer: Deserializer<ReadReader<Cursor<Vec<u8>>>>, dt: DateTime<Utc>, st: u32, et: u32) -> MyCursor {
+    pub fn new(db: Database, tb: String, dt: DateTime<Utc>, st: u32, et: u32) -> MyCursor {
         MyCursor {
             database:   db,
             table:      tb,
-            de:         deserializer,
+            records:    VecDeque::new(),
+            corrupt:    None,
             curr_ts:    dt,
             start_ts:   st,
-            end_ts:     et
+            end_ts:     et,
+            after:      None,
+            position:   None,
+            filter:     None
         }
     }
 
+    /// set_filter()
+    ///
+    /// Only returns records whose datalog matches the filter from now on
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = Some(filter);
+    }
+
+    /// continuation()
+    ///
+    /// Returns where the cursor stopped, None before the first record
+    pub fn continuation(&self) -> Option<Continuation> {
+        self.position.map(|(shard, offset, last_id)| Continuation {
+            table:      self.table.clone(),
+            shard:      shard.timestamp() as u32,
+            offset:     offset as u32,
+            last_id
+        })
+    }
+
+    /// next()
+    ///
+    /// Sets 'record' to the next record, or None once there is nothing
+    /// more to read. Corrupt parts of hour files are logged and skipped.
     pub fn next(&mut self, record: &mut Option<MpdRecordType>) {
         loop {
-            // Check if the end was reached
-            if cursor_is_end(self) {
-                *record = None;
+            match self.try_next() {
+                Ok(next) => *record = next,
+                Err(error) => {
+                    error!("{}", error);
+                    if error.kind() == ErrorKind::InvalidData {
+                        continue;
+                    }
+                    *record = None;
+                }
             }
+            return;
+        }
+    }
 
-            // Attempt to deserialize
-            let entry: MpdRecordType = match Deserialize::deserialize(&mut self.de) {
-                Ok(entry) => entry,
-                Err(error) => {
-                    match error {
-                        // End of file error, continue to next file *Note: other causes may trigger this*
-                        rmps::decode::Error::InvalidMarkerRead(_) => {},
-                        // Every other error, raise error and continue
-                        _ => error!("Unexpected Error! {:?}\nIgnoring...", error),
+    /// try_next()
+    ///
+    /// Returns the next record, None once there is nothing more to read,
+    /// or an error for an hour file that couldn't be read. A corrupt file is
+    /// reported as a `CorruptFile` after its valid records, reading can go
+    /// on with the next file afterwards.
+    pub fn try_next(&mut self) -> Result<Option<MpdRecordType>, io::Error> {
+        loop {
+            // Take the next record of the current file
+            let (offset, entry) = match self.records.pop_front() {
+                Some(entry) => entry,
+                None => {
+                    if let Some(corrupt) = self.corrupt.take() {
+                        return Err(Error::new(ErrorKind::InvalidData, corrupt));
                     }
+                    if cursor_is_end(self) {
+                        return Ok(None);
+                    }
+
                     // Add an hour of time and continue
-                    self.curr_ts = self.curr_ts + Duration::hours(1); 
+                    self.curr_ts = self.curr_ts + Duration::hours(1);
                     // Check if there exists another file
-                    match get_next_file(self) {
-                        Ok(buf) => {
-                            self.de = Deserializer::new(Cursor::new(buf));
+                    match get_next_file(self)? {
+                        Some(buf) => {
+                            let (mut records, corrupt) = decode_file(&buf);
+                            // Leave out what was returned before the cursor was resumed
+                            if let Some(after) = &self.after {
+                                if after.shard as i64 == self.curr_ts.timestamp() {
+                                    records.retain(|(offset, entry)| (entry.id, *offset) > (after.last_id, after.offset as usize));
+                                }
+                            }
+                            self.records = records;
+                            self.corrupt = corrupt.map(|(offset, reason)| CorruptFile {
+                                table: self.table.clone(),
+                                hour: self.curr_ts,
+                                offset,
+                                reason
+                            });
                         },
-                        Err(_) => {
+                        None => {
                             info!("Couldn't get another file, exiting loop.");
-                            *record = None; 
-                            return;
+                            return Ok(None);
                         }
                     }
                     continue;
@@ -120,64 +273,166 @@ impl MyCursor {
                 continue;
             }
 
-            // Check if entry ID is biiger than end_timestamp
+            // Check if entry ID is biiger than end_timestamp, records are sorted
+            // and later files only hold later hours so nothing else can match
             if entry.id > self.end_ts {
                 info!("Reached end time");
-                *record = None;
+                self.records.clear();
+                self.curr_ts = get_datetime(self.end_ts) + Duration::hours(1);
+                continue;
+            }
+
+            // Leave out records the filter doesn't match
+            if let Some(filter) = &self.filter {
+                if !filter.matches_datalog(&entry.datalog) {
+                    continue;
+                }
+            }
+
+            self.position = Some((self.curr_ts, offset, entry.id));
+            return Ok(Some(entry));
+        }
+    }
+}
+
+/// decode_file()
+///
+/// Decodes every record of an hour file with its byte offset and sorts them
+/// by id, since late records may have been appended after newer ones.
+///
+/// Decoding stops at the first bytes that aren't a record, records with
+/// a wrong checksum are left out. The offset and reason of the first
+/// corruption are returned with the records.
+pub(crate) fn decode_file(buf: &[u8]) -> (FileRecords, Option<(usize, String)>) {
+    let mut records: Vec<(usize, MpdRecordType)> = Vec::new();
+    let mut corrupt: Option<(usize, String)> = None;
+    let mut offset = 0;
+    while offset < buf.len() {
+        // Find where the record ends before decoding it, so a corrupt length can't allocate past the file
+        let length = match record_length(&buf[offset..]) {
+            Ok(length) => length,
+            Err(reason) => {
+                corrupt = Some((offset, reason.to_string()));
                 break;
             }
+        };
+        match rmps::from_read_ref::<_, MpdRecordType>(&buf[offset..offset + length]) {
+            Ok(entry) => {
+                if entry.checksum == crc32::checksum_ieee(&entry.datalog) {
+                    records.push((offset, entry));
+                } else if corrupt.is_none() {
+                    corrupt = Some((offset, format!("checksum mismatch for record {}", entry.id)));
+                }
+            },
+            Err(error) => {
+                corrupt = Some((offset, error.to_string()));
+                break;
+            }
+        }
+        offset += length;
+    }
 
-            // Set record and return
-            *record = Some(entry);
-            break;
-    
+    // Stable sort keeps records with the same id in the order they were written
+    records.sort_by_key(|(_, entry)| entry.id);
+    return (records.into(), corrupt);
+}
+
+/// record_length()
+///
+/// Walks the MsgPack value at the start of 'buf' and returns its length in bytes
+pub(crate) fn record_length(buf: &[u8]) -> Result<usize, &'static str> {
+    let mut offset = 0;
+    let mut pending: Vec<u64> = vec![1];  // Values left to read at every depth
+    while let Some(left) = pending.last_mut() {
+        if *left == 0 {
+            pending.pop();
+            continue;
+        }
+        *left -= 1;
+
+        let marker = *buf.get(offset).ok_or("file ends inside a record")?;
+        offset += 1;
+        let (size, children): (usize, u64) = match marker {
+            0x00..=0x7f | 0xe0..=0xff | 0xc0 | 0xc2 | 0xc3 => (0, 0),
+            0x80..=0x8f => (0, 2 * u64::from(marker & 0x0f)),
+            0x90..=0x9f => (0, u64::from(marker & 0x0f)),
+            0xa0..=0xbf => (usize::from(marker & 0x1f), 0),
+            0xc1 => return Err("reserved marker"),
+            0xc4 | 0xd9 => (read_length(buf, &mut offset, 1)?, 0),
+            0xc5 | 0xda => (read_length(buf, &mut offset, 2)?, 0),
+            0xc6 | 0xdb => (read_length(buf, &mut offset, 4)?, 0),
+            0xc7 => (read_length(buf, &mut offset, 1)? + 1, 0),
+            0xc8 => (read_length(buf, &mut offset, 2)? + 1, 0),
+            0xc9 => (read_length(buf, &mut offset, 4)? + 1, 0),
+            0xca => (4, 0),
+            0xcb => (8, 0),
+            0xcc | 0xd0 => (1, 0),
+            0xcd | 0xd1 => (2, 0),
+            0xce | 0xd2 => (4, 0),
+            0xcf | 0xd3 => (8, 0),
+            0xd4 => (2, 0),
+            0xd5 => (3, 0),
+            0xd6 => (5, 0),
+            0xd7 => (9, 0),
+            0xd8 => (17, 0),
+            0xdc => (0, read_length(buf, &mut offset, 2)? as u64),
+            0xdd => (0, read_length(buf, &mut offset, 4)? as u64),
+            0xde => (0, 2 * read_length(buf, &mut offset, 2)? as u64),
+            0xdf => (0, 2 * read_length(buf, &mut offset, 4)? as u64),
+        };
+        if size > buf.len() - offset {
+            return Err("file ends inside a record");
+        }
+        offset += size;
+        if children > 0 {
+            if pending.len() >= MAX_RECORD_DEPTH {
+                return Err("record is nested too deep");
+            }
+            pending.push(children);
         }
     }
+    return Ok(offset);
 }
- 
-/// get_next_file()
+
+/// read_length()
 ///
-/// gets the next file in the database and returns error
-/// if there is nothing to read
-#[allow(unused_assignments)] // for curr_directory and curr_file 
-fn get_next_file(cursor: &mut MyCursor) -> Result<Vec<u8>, Error> {
-    // Setup variables
-    let mut curr_directory = String::new();
-    let mut curr_file = String::new();
-    let mut buf = Vec::new();
+/// Reads a big endian length of 'bytes' bytes at 'offset'
+fn read_length(buf: &[u8], offset: &mut usize, bytes: usize) -> Result<usize, &'static str> {
+    let data = buf.get(*offset..*offset + bytes).ok_or("file ends inside a record")?;
+    *offset += bytes;
+    return Ok(data.iter().fold(0, |length, byte| (length << 8) | usize::from(*byte)));
+}
 
+/// get_next_file()
+///
+/// gets the next file in the database, None if there is nothing to read
+fn get_next_file(cursor: &mut MyCursor) -> Result<Option<Vec<u8>>, Error> {
     loop {
-        buf.clear();
-        curr_directory = format!("{}/{}/{}", cursor.database.source, cursor.table, cursor.curr_ts.format(DATE_FORMAT));
-        curr_file = format!("{}/{}", curr_directory, cursor.curr_ts.format(TIME_FORMAT));
-
-        // Check if Directory doesn't exist
-        if !Path::new(&curr_directory).exists() {
-            // Add a day of time, set hours, minutes and seconds to 0 and continue
-            cursor.curr_ts = (cursor.curr_ts + Duration::days(1)).date().and_hms(0, 0, 0);  // += gives error 
-            if cursor_is_end(cursor) {
-                return Err(Error::new(ErrorKind::Other, "Nothing more to read."));
+        if cursor_is_end(cursor) {
+            return Ok(None);
+        }
+
+        // Skip ahead to the next hour that has a file
+        let end = get_datetime(cursor.end_ts);
+        match cursor.database.storage.next_shard(&cursor.table, &cursor.curr_ts, &end)? {
+            Some(shard) => cursor.curr_ts = shard,
+            None => {
+                cursor.curr_ts = end + Duration::hours(1);
+                return Ok(None);
             }
-            continue;
         }
 
-        // Check if File doesn't exist
-        if !Path::new(&curr_file).exists() {
-            // Add an hour of time and continue
-            cursor.curr_ts = cursor.curr_ts + Duration::hours(1);  // += gives error
-            if cursor_is_end(cursor) {
-                return Err(Error::new(ErrorKind::Other, "Nothing more to read."));
+        // Read File while holding the table's read lock so a partial append is never seen
+        let lock = cursor.database.table_lock(&cursor.table);
+        let _guard = lock.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match cursor.database.storage.read_shard(&cursor.table, &cursor.curr_ts)? {
+            Some(buf) => return Ok(Some(buf)),
+            None => {
+                // Removed since it was found, add an hour of time and continue
+                cursor.curr_ts = cursor.curr_ts + Duration::hours(1);  // += gives error
             }
-            continue;
         }
-        
-        // Read File
-        let mut file = File::open(curr_file).unwrap();
-        file.read_to_end(&mut buf).unwrap();
-        break;
     }
-
-    return Ok(buf);
 }
 
 /// cursor_is_end()
@@ -193,198 +448,328 @@ fn cursor_is_end(cursor: &mut MyCursor) -> bool {
 /// Implementation of Database
 impl Database {
     /// Constructor
-    pub fn new(source: &'static str) -> Database {
+    ///
+    /// Opens the source for writing, failing if another process
+    /// already has it open for writing
+    pub fn new<P: AsRef<Path>>(source: P) -> Result<Database, io::Error> {
+        let source = source.as_ref().to_path_buf();
+        create_dir_all(&source)?;
+        let state = source_state(&source)?;
+
+        // Take the writer lock unless this process already holds it
+        let mut lock_file = state.lock_file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if lock_file.is_none() {
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(source.join(LOCK_FILE))?;
+            if let Err(err) = file.try_lock_exclusive() {
+                error!("Could not lock {:?}: {:?}", source, err);
+                return Err(Error::new(ErrorKind::WouldBlock, format!("Database source {:?} is already opened for writing by another process", source)));
+            }
+            *lock_file = Some(file);
+        }
+        drop(lock_file);
+
+        Ok(Database {
+            storage: Arc::new(FsStorage::new(&source)),
+            source,
+            read_only: false,
+            state
+        })
+    }
+
+    /// open_read_only()
+    ///
+    /// Opens an existing source without taking the writer lock, so
+    /// tools can read it while another process is writing to it
+    pub fn open_read_only<P: AsRef<Path>>(source: P) -> Result<Database, io::Error> {
+        let source = source.as_ref().to_path_buf();
+        if !source.is_dir() {
+            return Err(Error::new(ErrorKind::NotFound, format!("Database source {:?} does not exist", source)));
+        }
+
+        Ok(Database {
+            state: source_state(&source)?,
+            storage: Arc::new(FsStorage::new(&source)),
+            source,
+            read_only: true
+        })
+    }
+
+    /// with_storage()
+    ///
+    /// Creates a database kept by any storage
+    pub fn with_storage(storage: Arc<dyn Storage>) -> Database {
         Database {
-            source: source
+            source: PathBuf::new(),
+            read_only: false,
+            state: Arc::new(SourceState::default()),
+            storage
+        }
+    }
+
+    /// in_memory()
+    ///
+    /// Creates an empty database kept in memory
+    pub fn in_memory() -> Database {
+        Database::with_storage(Arc::new(MemoryStorage::new()))
+    }
+
+    /// storage()
+    ///
+    /// Returns the storage keeping the records
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    /// is_read_only()
+    ///
+    /// Returns true if this handle can't modify the database
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// table_lock()
+    ///
+    /// Returns the lock guarding a table, creating it on first use
+    pub(crate) fn table_lock(&self, table: &str) -> Arc<RwLock<()>> {
+        let mut tables = self.state.tables.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return tables.entry(table.to_string()).or_default().clone();
+    }
+
+    /// check_writable()
+    ///
+    /// Returns an error if this handle was opened read-only
+    pub(crate) fn check_writable(&self) -> Result<(), io::Error> {
+        if self.read_only {
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("Database source {:?} was opened read-only", self.source)));
         }
+        Ok(())
     }
 
     /// set_source()
     ///
     /// Set a new source for the database
-    #[allow(dead_code)]
-    pub fn set_source(&mut self, source: &'static str) -> Result<(), io::Error> {
-        self.source = source;
+    pub fn set_source<P: AsRef<Path>>(&mut self, source: P) -> Result<(), io::Error> {
+        *self = match self.read_only {
+            true => Database::open_read_only(source)?,
+            false => Database::new(source)?
+        };
         Ok(())
     }
     
     /// list_db()
     ///
-    /// Lists all the databases within the current data source
-    #[allow(dead_code)]
-    pub fn list_db(&self) {
-        print_directories(self.source, 0);
+    /// Lists all the tables and their days within the current data source
+    pub fn list_db(&self) -> Result<(), io::Error> {
+        for table in self.storage.list_tables()? {
+            println!("{}", table);
+            let mut days: Vec<String> = self.storage.list_shards(&table)?.iter().map(|shard| shard.format(DATE_FORMAT).to_string()).collect();
+            days.dedup();
+            for day in days {
+                println!("-{}", day);
+            }
+        }
+        Ok(())
     }
 
     /// insert_at()
     ///
     /// Insert into database
-    #[allow(dead_code)]
     pub fn insert_at(&self, path: &str, file: &str, entry: Entry) -> Result<(), io::Error> {
-        // Variables
-        let ymd = String::from(path);
-        let h = String::from(file);
-
-        // Set the directory
-        let mut directory = format!("{}/{}/{}", 
-                    self.source,    // Database Directory
-                    entry.table,    // Sub directory
-                    path            // Current format of time
-                );
-        info!("Directory is: {:?}", directory);
-
-        // Ensure directory/file exists
-        create_dir_all(&directory).unwrap();
-        directory.push_str(&format!("/{}", file));
-        let path = Path::new(&mut directory);
-        if !path.exists() {
-            File::create(&directory)?;
-            info!("File created!\n");
-        }
+        self.check_writable()?;
+        validate_table_name(&entry.table)?;
+        validate_relative_path(&format!("{}/{}", path, file))?;
 
-        let dt = Utc.ymd(ymd[0..4].parse::<i32>().unwrap(), ymd[4..6].parse::<u32>().unwrap(), ymd[6..8].parse::<u32>().unwrap()).and_hms(h.parse::<u32>().unwrap(), 0, 0);
         // Set up data
-        let new_data = MpdRecordType{
-            id:         dt.timestamp() as u32,
-            datalog:    entry.data.clone(),
-            checksum:   crc32::checksum_ieee(&entry.data)
-        };
-        let serialized_data = serialize_struct(new_data).unwrap();
+        let dt = storage::parse_shard(path, file).ok_or_else(|| invalid_name(&format!("{}/{}", path, file), "not a date and hour"))?;
+        self.insert_record(&entry.table, MpdRecordType::new(dt.timestamp() as u32, entry.data))
+    }
 
-        // Write to database
-        let mut file = OpenOptions::new().append(true).open(&directory)?;   // Write at end of file
-        file.write_all(&serialized_data)?;
+    /// insert_record()
+    ///
+    /// Append a record to the hour file its id (timestamp) falls in
+    pub fn insert_record(&self, table: &str, record: MpdRecordType) -> Result<(), io::Error> {
+        self.check_writable()?;
+        validate_table_name(table)?;
+
+        let datetime = get_datetime(record.id);
+        let hour = datetime.date().and_hms(datetime.hour(), 0, 0);
+        let serialized_data = serialize_struct(record)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Could not serialize record"))?;
+
+        // Only one writer per table at a time
+        let lock = self.table_lock(table);
+        let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // Write at end of file
+        self.storage.append(table, &hour, &serialized_data)?;
         info!("Wrote: {:?}\n", serialized_data);
         Ok(())
     }
 
     /// insert()
     ///
-    /// Insert into database
-    #[allow(dead_code)]
+    /// Insert into database at the current time
     pub fn insert(&self, entry: Entry) -> Result<(), io::Error> {
-        // Set the directory
-        let directory = format!("{}/{}/{}/{}", 
-                    self.source,                     // Database Directory
-                    entry.table,                     // Sub directory
-                    get_local_datetime(DATE_FORMAT), // Current format of data Ex: &Y&m&d -> 19700101
-                    get_local_datetime(TIME_FORMAT)  // Current format of time
-                );
-        println!("{:?}", directory);
-        // Check if exists
-        if !Path::new(&directory).exists() {
-            File::create(&directory)?;
-            info!("File created!\n")
-        }
-        
-
-        // Write to database
-        let mut file = OpenOptions::new().append(true).open(&directory)?;   // Write at end of file
-        file.write_all(&entry.data)?;
-        info!("Wrote: {:?}\n", entry.data);
-        Ok(())
+        self.insert_record(&entry.table, MpdRecordType::new(Utc::now().timestamp() as u32, entry.data))
     }
 
     /// find_file()
     ///
-    /// Find a particular file/folder
-    #[allow(dead_code)]
+    /// Find a particular hour file, e.g. "levels/20200101/22"
     pub fn find_file(&self, source: &str) -> Result<Vec<u8>, io::Error> {
-        // Set the directory
-        let mut directory = String::new();
-        directory.push_str(self.source);    // Database Directory
-        directory.push_str(source);         // Sub directory
+        // The path is relative to the database even with a leading '/'
+        let (table, shard) = parse_shard_path(source.trim_start_matches('/'))?;
 
         // Read from file
-        let mut buf: Vec<u8> = Vec::new();
-        let mut file = File::open(&mut directory)?;
-        file.read_to_end(&mut buf)?;
-        info!("Read: {:?}\n", buf);
-        return Ok(buf);
+        let lock = self.table_lock(&table);
+        let _guard = lock.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match self.storage.read_shard(&table, &shard)? {
+            Some(buf) => {
+                info!("Read: {:?}\n", buf);
+                return Ok(buf);
+            },
+            None => return Err(Error::new(ErrorKind::NotFound, format!("No file {:?}", source)))
+        }
     }
 
     /// delete_file()
     ///
-    /// Remove a particular file
-    #[allow(dead_code)]
-    pub fn delete_file(&self, table: &'static str, source: &'static str) -> io::Result<()> {
-        let file = format!("{}/{}/{}", self.source, table, source);
-        remove_file(file)?;
-        Ok(())
-    }
-
-    /// find_data()
-    ///
-    /// Find a particular Entry
-    #[allow(dead_code, unused_variables)]
-    pub fn find_data(&self, date: &str) {
-        
+    /// Remove a particular hour file, e.g. ("levels", "20200101/22")
+    pub fn delete_file(&self, table: &str, source: &str) -> io::Result<()> {
+        self.check_writable()?;
+        validate_table_name(table)?;
+        let (_, shard) = parse_shard_path(&format!("{}/{}", table, source))?;
+        let lock = self.table_lock(table);
+        let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.storage.delete_shard(table, &shard)
     }
 
     /// Different implementation of get_data can be found here: https://pastebin.com/z2pbbQxy
     /// 
     /// get_data()
     ///
-    /// Grabs data from the database 
-    pub fn get_data(&self, table: &'static str, start_time: u32, end_time: u32) -> MyCursor {
-        let start_time = start_time - 3600; // an hour of time is taken off to account for initial failure adding an hour of time
-        let cursor = MyCursor::new(Database::new(self.source), table, Deserializer::new(Cursor::new(Vec::new())), get_datetime(start_time), start_time, end_time);
-        return cursor;
+    /// Grabs the records of a table with start_time <= id <= end_time, in order
+    pub fn get_data(&self, table: &str, start_time: u32, end_time: u32) -> Result<MyCursor, io::Error> {
+        validate_table_name(table)?;
+
+        // The cursor adds an hour before reading its first file, so it starts an hour before the start's file
+        let start = get_datetime(start_time);
+        let before_start = start.date().and_hms(start.hour(), 0, 0) - Duration::hours(1);
+        let cursor = MyCursor::new(self.clone(), table.to_string(), before_start, start_time, end_time);
+        return Ok(cursor);
+    }
+
+    /// resume()
+    ///
+    /// Returns a cursor over the records after a continuation, up to 'end_time'
+    pub fn resume(&self, after: &Continuation, end_time: u32) -> Result<MyCursor, io::Error> {
+        let mut cursor = self.get_data(&after.table, after.last_id, end_time)?;
+        cursor.after = Some(after.clone());
+        return Ok(cursor);
+    }
+
+    /// aggregate()
+    ///
+    /// Returns the statistics of a table's records with start_time <= id <= end_time
+    /// in buckets of 'bucket_secs', for the fields in 'fields' or all numeric ones
+    pub fn aggregate(&self, table: &str, start_time: u32, end_time: u32, bucket_secs: u32, fields: Vec<String>) -> Result<Aggregator, io::Error> {
+        Aggregator::new(self.get_data(table, start_time, end_time)?, bucket_secs, fields)
+    }
+
+    /// table()
+    ///
+    /// Returns a typed handle to a table
+    pub fn table<T: Serialize + DeserializeOwned>(&self, name: &str) -> Table<T> {
+        Table::new(self.clone(), name)
     }
 }
 
-/// read()
+/// validate_table_name()
 ///
-/// reads directory
-#[allow(dead_code)]
-fn read(mut buf: &mut Vec<u8>, mut directory: &mut String) -> std::io::Result<()> {
-    let mut file = File::open(&mut directory)?;
-    file.read_to_end(&mut buf)?;
-    info!("Read: {:?}\n", buf);
+/// Checks that a table name is a single, plain directory name.
+/// Only ASCII letters, digits, '_' and '-' are allowed.
+pub fn validate_table_name(table: &str) -> Result<(), io::Error> {
+    if table.is_empty() {
+        return Err(invalid_name(table, "name is empty"));
+    }
+    if table.len() > MAX_NAME_LENGTH {
+        return Err(invalid_name(table, "name is too long"));
+    }
+    if !table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(invalid_name(table, "only letters, digits, '_' and '-' are allowed"));
+    }
     Ok(())
 }
 
-/// print_directories()
+/// validate_relative_path()
 ///
-/// prints all the directories not including files
-fn print_directories(path: &str, count: usize) {
-    let paths = fs::read_dir(path).unwrap();
-
-    for entry in paths {
-        if let Ok(entry) = entry {
-            if entry.path().is_dir() {
-                // Print Directory
-                print!("{:-<1$}", "", count);
-                println!("{}", entry.file_name().into_string().unwrap());
-                print_directories(entry.path().to_str().unwrap(), count + 1);
-            }
+/// Checks that a path stays inside the directory it is relative to.
+/// Every component has to be a plain name like a table name, or a file name with a '.'
+pub fn validate_relative_path(path: &str) -> Result<(), io::Error> {
+    if path.is_empty() {
+        return Err(invalid_name(path, "path is empty"));
+    }
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => {
+                let plain = match name.to_str() {
+                    Some(name) => !name.starts_with('.') && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.'),
+                    None => false
+                };
+                if !plain {
+                    return Err(invalid_name(path, "path has a component that is not a plain name"));
+                }
+            },
+            Component::ParentDir => return Err(invalid_name(path, "path can't contain '..'")),
+            Component::CurDir => return Err(invalid_name(path, "path can't contain '.'")),
+            _ => return Err(invalid_name(path, "path has to be relative"))
         }
     }
+    Ok(())
 }
 
-/// print_db()
+/// parse_shard_path()
 ///
-/// prints all the directories and files
-#[allow(dead_code)]
-fn print_db(path: &str, count: usize) {
-    let paths = fs::read_dir(path).unwrap();
-
-    for entry in paths {
-        if let Ok(entry) = entry {
-            // Print Directory
-            print!("{:-<1$}", "", count);
-            println!("{}", entry.file_name().into_string().unwrap());
-            if entry.path().is_dir() {
-                print_directories(entry.path().to_str().unwrap(), count + 1);
-            }
-        }
+/// Splits a path like "levels/20200101/22" into its table and hour
+fn parse_shard_path(path: &str) -> Result<(String, DateTime<Utc>), io::Error> {
+    validate_relative_path(path)?;
+    let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+    if parts.len() != 3 {
+        return Err(invalid_name(path, "path has to be <table>/<YYYYmmdd>/<HH>"));
+    }
+    validate_table_name(parts[0])?;
+    match storage::parse_shard(parts[1], parts[2]) {
+        Some(shard) => Ok((parts[0].to_string(), shard)),
+        None => Err(invalid_name(path, "not a date and hour"))
     }
 }
 
+/// invalid_name()
+///
+/// Creates the error for an invalid table name or path
+pub(crate) fn invalid_name(name: &str, reason: &'static str) -> Error {
+    Error::new(ErrorKind::InvalidInput, InvalidName { name: name.to_string(), reason })
+}
+
+/// source_state()
+///
+/// Returns the state shared by every handle to a source in this process
+fn source_state(source: &Path) -> Result<Arc<SourceState>, io::Error> {
+    let path = fs::canonicalize(source)?;
+    let mut sources = OPEN_SOURCES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(state) = sources.get(&path).and_then(Weak::upgrade) {
+        return Ok(state);
+    }
+
+    let state = Arc::new(SourceState::default());
+    sources.insert(path, Arc::downgrade(&state));
+    return Ok(state);
+}
+
 /// serialize_struct()
 ///
 /// Serializes structs
-fn serialize_struct<T>(data: T) -> Result<Vec<u8>, ()> where T: Serialize, {
+pub(crate) fn serialize_struct<T>(data: T) -> Result<Vec<u8>, ()> where T: Serialize, {
     let mut buf = Vec::new();
     let mut msg_pack = Serializer::new(&mut buf);
     match data.serialize(&mut msg_pack) {
@@ -398,41 +783,21 @@ fn serialize_struct<T>(data: T) -> Result<Vec<u8>, ()> where T: Serialize, {
     
 }
 
-/// get_local_datetime()
-///
-/// Returns the current time UTC
-fn get_local_datetime(format: &str) -> String {
-    let local: DateTime<Utc> = Utc::now();
-    return local.format(format).to_string();
-}
-
 /// get_datetime()
 ///
 /// Converts timestamp to datetime
-fn get_datetime(timestamp: u32) -> DateTime<Utc> {
+pub(crate) fn get_datetime(timestamp: u32) -> DateTime<Utc> {
     let naive_datetime = NaiveDateTime::from_timestamp(i64::from(timestamp), 0);  // the 0 represents nanoseconds for leap seconds
     let utc_datetime = DateTime::<Utc>::from_utc(naive_datetime, Utc);
     return utc_datetime;
 }
 
-/// print_error()
-///
-/// Prints custom errors
-#[allow(dead_code)]
-fn print_error(err: Error) {
-    if let Some(inner_err) = err.into_inner() {
-        error!("Inner error: {}", inner_err);
-    } else {
-        error!("No inner error");
-    }
-}
-
 /** 
 /*************************************************** TESTS **************************************************/
 */
 use rand::Rng;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
 #[allow(non_snake_case)]
 pub struct RawData { // change all names
     pub AQHI:		Option<i32>,
@@ -452,10 +817,14 @@ pub struct RawData { // change all names
 	pub TimeStamp:	Option<String> // change ~ ticks
 }
 
+/// Names of the RawData fields, in the order RawData is encoded as an array
+pub const RAW_DATA_FIELDS: [&str; 15] = [
+    "AQHI", "AQI", "CO", "CO2", "NO", "NO2", "O3", "PM1", "PM2_5", "PM10", "SO2", "T", "RH", "NOISE", "TimeStamp"
+];
+
 /// new_buf()
 ///
 /// Serialize a randomly generated struct
-#[allow(dead_code)]
 pub fn new_buf() -> Result<Vec<u8>, Error> {
     match serialize_struct(generate_raw_data()) {
         Ok(buf) => return Ok(buf),
@@ -491,7 +860,6 @@ fn generate_raw_data() -> RawData {
 /// generate_i32()
 ///
 /// Generates random i32 from 0-10, if it's greater than 8, return null
-#[allow(dead_code)]
 fn generate_i32() -> Option<i32> {
     let mut rng = rand::thread_rng();
     let num: i32 = rng.gen_range(0,10);
@@ -504,7 +872,6 @@ fn generate_i32() -> Option<i32> {
 /// generate_f32()
 ///
 /// Generates random f32 from 0-10, if it's greater than 8, return null
-#[allow(dead_code)]
 fn generate_f32() -> Option<f32> {
     let mut rng = rand::thread_rng();
     let num: f32 = rng.gen_range(0.0,10.0);
@@ -516,8 +883,10 @@ fn generate_f32() -> Option<f32> {
 
 #[cfg(test)]
 mod file_sys_tests {
-    #[allow(unused_imports)]
     use super::*;
+    use crate::fixtures::{count, ShardBuilder, TempDatabase};
+    use std::thread;
+    use fs2::FileExt;
 
     // #[test]
     // fn test1_get_data() {
@@ -526,15 +895,15 @@ mod file_sys_tests {
     //     let database = Database::new("data");
 
     //     let mut buf: Vec<u8> = new_buf().unwrap();
-    //     database.insert_at("20200101", "22", Entry{table: "levels", data: buf}).unwrap();
+    //     database.insert_at("20200101", "22", Entry{table: "levels".to_string(), data: buf}).unwrap();
     //     buf = new_buf().unwrap();
-    //     database.insert_at("20200101", "23", Entry{table: "levels", data: buf}).unwrap();
+    //     database.insert_at("20200101", "23", Entry{table: "levels".to_string(), data: buf}).unwrap();
     //     buf = new_buf().unwrap();
-    //     database.insert_at("20200102", "00", Entry{table: "levels", data: buf}).unwrap();
+    //     database.insert_at("20200102", "00", Entry{table: "levels".to_string(), data: buf}).unwrap();
     //     buf = new_buf().unwrap();
-    //     database.insert_at("20200102", "01", Entry{table: "levels", data: buf}).unwrap();
+    //     database.insert_at("20200102", "01", Entry{table: "levels".to_string(), data: buf}).unwrap();
 
-    //     database.get_data("levels", 1577916000, 1577926800);
+    //     database.get_data("levels", 1577916000, 1577926800).unwrap();
 
     //     database.delete_file("levels", "20200101/22").unwrap();
     //     database.delete_file("levels", "20200101/23").unwrap();
@@ -551,21 +920,21 @@ mod file_sys_tests {
     //     let database = Database::new("data");
 
     //     let mut buf: Vec<u8> = new_buf().unwrap();
-    //     database.insert_at("20200101", "22", Entry{table: "levels", data: buf}).unwrap();
+    //     database.insert_at("20200101", "22", Entry{table: "levels".to_string(), data: buf}).unwrap();
     //     buf = new_buf().unwrap();
-    //     database.insert_at("20200101", "22", Entry{table: "levels", data: buf}).unwrap();
+    //     database.insert_at("20200101", "22", Entry{table: "levels".to_string(), data: buf}).unwrap();
     //     buf = new_buf().unwrap();
-    //     database.insert_at("20200101", "23", Entry{table: "levels", data: buf}).unwrap();
+    //     database.insert_at("20200101", "23", Entry{table: "levels".to_string(), data: buf}).unwrap();
     //     buf = new_buf().unwrap();
-    //     database.insert_at("20200101", "23", Entry{table: "levels", data: buf}).unwrap();
+    //     database.insert_at("20200101", "23", Entry{table: "levels".to_string(), data: buf}).unwrap();
     //     buf = new_buf().unwrap();
-    //     database.insert_at("20200102", "00", Entry{table: "levels", data: buf}).unwrap();
+    //     database.insert_at("20200102", "00", Entry{table: "levels".to_string(), data: buf}).unwrap();
     //     buf = new_buf().unwrap();
-    //     database.insert_at("20200102", "00", Entry{table: "levels", data: buf}).unwrap();
+    //     database.insert_at("20200102", "00", Entry{table: "levels".to_string(), data: buf}).unwrap();
     //     buf = new_buf().unwrap();
-    //     database.insert_at("20200102", "01", Entry{table: "levels", data: buf}).unwrap();
+    //     database.insert_at("20200102", "01", Entry{table: "levels".to_string(), data: buf}).unwrap();
 
-    //     database.get_data("levels", 1577916000, 1577926800);
+    //     database.get_data("levels", 1577916000, 1577926800).unwrap();
 
     //     database.delete_file("levels", "20200101/22").unwrap();
     //     database.delete_file("levels", "20200101/23").unwrap();
@@ -581,13 +950,13 @@ mod file_sys_tests {
 
     //     let database = Database::new("data");
 
-    //     File::create(format!("{}/{}", database.source, "levels/20200101/22")).unwrap();
-    //     File::create(format!("{}/{}", database.source, "levels/20200101/23")).unwrap();
-    //     File::create(format!("{}/{}", database.source, "levels/20200102/00")).unwrap();
+    //     File::create(database.source.join("levels/20200101/22")).unwrap();
+    //     File::create(database.source.join("levels/20200101/23")).unwrap();
+    //     File::create(database.source.join("levels/20200102/00")).unwrap();
     //     let buf: Vec<u8> = new_buf().unwrap();
-    //     database.insert_at("20200102", "01", Entry{table: "levels", data: buf}).unwrap();
+    //     database.insert_at("20200102", "01", Entry{table: "levels".to_string(), data: buf}).unwrap();
 
-    //     database.get_data("levels", 1577916000, 1577926800);
+    //     database.get_data("levels", 1577916000, 1577926800).unwrap();
 
     //     database.delete_file("levels", "20200101/22").unwrap();
     //     database.delete_file("levels", "20200101/23").unwrap();
@@ -604,11 +973,11 @@ mod file_sys_tests {
     //     let database = Database::new("data");
 
     //     let mut buf: Vec<u8> = new_buf().unwrap();
-    //     database.insert_at("20200101", "22", Entry{table: "levels", data: buf}).unwrap();
+    //     database.insert_at("20200101", "22", Entry{table: "levels".to_string(), data: buf}).unwrap();
     //     buf = new_buf().unwrap();
-    //     database.insert_at("20200102", "00", Entry{table: "levels", data: buf}).unwrap();
+    //     database.insert_at("20200102", "00", Entry{table: "levels".to_string(), data: buf}).unwrap();
 
-    //     database.get_data("levels", 1577916000, 1577926800);
+    //     database.get_data("levels", 1577916000, 1577926800).unwrap();
 
     //     database.delete_file("levels", "20200101/22").unwrap();
     //     database.delete_file("levels", "20200102/00").unwrap();
@@ -623,15 +992,510 @@ mod file_sys_tests {
     //     let database = Database::new("data");
 
     //     let mut buf: Vec<u8> = new_buf().unwrap();
-    //     database.insert_at("20200102", "00", Entry{table: "levels", data: buf}).unwrap();
+    //     database.insert_at("20200102", "00", Entry{table: "levels".to_string(), data: buf}).unwrap();
     //     buf = new_buf().unwrap();
-    //     database.insert_at("20200102", "01", Entry{table: "levels", data: buf}).unwrap();
+    //     database.insert_at("20200102", "01", Entry{table: "levels".to_string(), data: buf}).unwrap();
 
-    //     database.get_data("levels", 1577916000, 1577926800);
+    //     database.get_data("levels", 1577916000, 1577926800).unwrap();
 
     //     database.delete_file("levels", "20200102/00").unwrap();
     //     database.delete_file("levels", "20200102/01").unwrap();
 
     //     println!("Finished get_data test7!");
     // }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_cursor() {
+        println!("Starting test_cursor test!");
+
+        let database = TempDatabase::new();
+
+        // Create fake data
+        database.shards("levels")
+            .records("20200101", "22", 2)
+            .records("20200101", "23", 1)
+            .records("20200102", "00", 2)
+            .records("20200102", "01", 1);
+
+        assert_eq!(count(&database, "levels", 1577916000, 1577926800), 6);  // Was able to read all 6 entries
+
+        println!("Finished test_cursor test!");
+    }
+
+    #[test]
+    fn test2_cursor() {
+        println!("Starting test2_cursor test!");
+
+        let database = TempDatabase::new();
+
+        // Create fake data
+        database.shards("levels")
+            .records("20200101", "22", 2)
+            .records("20200101", "23", 2)
+            .records("20200102", "00", 2)
+            .records("20200102", "01", 1);
+
+        assert_eq!(count(&database, "levels", 1577916000, 1577926800), 7);  // Was able to read all 7 entries
+
+        println!("Finished test2_cursor test!");
+    }
+
+    #[test]
+    fn test3_cursor() {
+        println!("Starting test3_cursor test!");
+
+        let database = TempDatabase::new();
+
+        // Create fake data
+        database.shards("levels")
+            .empty("20200101", "22")
+            .empty("20200101", "23")
+            .empty("20200102", "00")
+            .records("20200102", "01", 1);
+
+        assert_eq!(count(&database, "levels", 1577916000, 1577926800), 1);  // Was able to read all 1 entries
+
+        println!("Finished test3_cursor test!");
+    }
+
+    #[test]
+    fn test4_cursor() {
+        println!("Starting test4_cursor test!");
+
+        let database = TempDatabase::new();
+
+        // Create fake data
+        database.shards("levels")
+            .records("20200101", "22", 1)
+            .records("20200102", "00", 1);
+
+        assert_eq!(count(&database, "levels", 1577916000, 1577926800), 2);  // Was able to read all 2 entries
+
+        println!("Finished test4_cursor test!");
+    }
+
+    #[test]
+    fn test5_cursor() {
+        println!("Starting test5_cursor test!");
+
+        let database = TempDatabase::new();
+
+        // Create fake data
+        database.shards("levels")
+            .records("20200102", "00", 1)
+            .records("20200102", "01", 1);
+
+        assert_eq!(count(&database, "levels", 1577916000, 1577926800), 2);  // Was able to read all 2 entries
+
+        println!("Finished test5_cursor test!");
+    }
+
+    #[test]
+    fn test_concurrent_cursor() {
+        println!("Starting test_concurrent_cursor test!");
+
+        let database = TempDatabase::new();
+        let database = Arc::new(database.clone());
+
+        // Write while another thread reads the same file
+        let writer_db = database.clone();
+        let writer = thread::spawn(move || {
+            for _ in 0..200 {
+                let buf: Vec<u8> = new_buf().unwrap();
+                writer_db.insert_at("20200103", "00", Entry{table: "threads".to_string(), data: buf}).unwrap();
+            }
+        });
+
+        let reader_db = database.clone();
+        let reader = thread::spawn(move || {
+            let mut last_count = 0;
+            while last_count < 200 {
+                let mut cursor = reader_db.get_data("threads", 1578009600, 1578013200).unwrap();
+                let mut record: Option<MpdRecordType> = None;
+                let mut count = 0;
+                loop {
+                    cursor.next(&mut record);
+                    match record.take() {
+                        // Every record read has to be complete
+                        Some(entry) => assert_eq!(entry.checksum, crc::crc32::checksum_ieee(&entry.datalog)),
+                        None => break
+                    }
+                    count += 1;
+                }
+                assert!(count >= last_count);
+                last_count = count;
+            }
+        });
+
+        writer.join().unwrap();
+        reader.join().unwrap();
+
+        println!("Finished test_concurrent_cursor test!");
+    }
+
+    #[test]
+    fn test_memory_cursor() {
+        println!("Starting test_memory_cursor test!");
+
+        let database = Database::in_memory();
+
+        // 2020-01-01 22:00 to 2020-01-02 01:00, nothing in between
+        ShardBuilder::new(&database, "levels").at(1577916000).at(1577926800);
+        assert_eq!(count(&database, "levels", 1577916000, 1577926800), 2);  // Was able to read both entries
+
+        assert_eq!(database.storage().list_tables().unwrap(), vec!["levels".to_string()]);
+        assert!(database.find_file("levels/20200101/22").is_ok());
+        database.delete_file("levels", "20200101/22").unwrap();
+        assert_eq!(database.find_file("levels/20200101/22").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        println!("Finished test_memory_cursor test!");
+    }
+
+    #[test]
+    fn test_writer_lock() {
+        println!("Starting test_writer_lock test!");
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path();
+
+        // Another process holding the writer lock
+        let lock = std::fs::OpenOptions::new().write(true).create(true).truncate(false).open(source.join(".lock")).unwrap();
+        lock.try_lock_exclusive().unwrap();
+
+        // A second writer is refused, readers are not
+        let error = Database::new(source).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::WouldBlock);
+        let reader = Database::open_read_only(source).unwrap();
+        assert!(reader.is_read_only());
+        let buf: Vec<u8> = new_buf().unwrap();
+        let error = reader.insert_at("20200101", "22", Entry{table: "levels".to_string(), data: buf}).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+
+        // Once released, the source can be opened for writing
+        lock.unlock().unwrap();
+        let writer = Database::new(source).unwrap();
+        assert!(!writer.is_read_only());
+
+        println!("Finished test_writer_lock test!");
+    }
+
+    #[test]
+    fn test_set_source() {
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+
+        // insert() stamps the record with the current time
+        let mut database = Database::new(first.path()).unwrap();
+        let before = Utc::now().timestamp() as u32;
+        database.insert(Entry{table: "levels".to_string(), data: new_buf().unwrap()}).unwrap();
+        let after = Utc::now().timestamp() as u32;
+        assert_eq!(count(&database, "levels", before, after), 1);
+        database.list_db().unwrap();
+
+        // Switching sources releases the first one
+        database.set_source(second.path()).unwrap();
+        assert_eq!(count(&database, "levels", before, after), 0);
+        assert!(Database::new(first.path()).is_ok());
+
+        // A reader stays a reader
+        let mut reader = Database::open_read_only(first.path()).unwrap();
+        reader.set_source(second.path()).unwrap();
+        assert!(reader.is_read_only());
+        assert_eq!(reader.set_source(first.path().join("missing")).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_name_validation() {
+        println!("Starting test_name_validation test!");
+
+        let database = TempDatabase::new();
+
+        // Names that could leave the table directory
+        for table in ["../../etc", "..", ".", "", "levels/20200101", "/etc", "le vels", ".hidden"].iter() {
+            let error = database.get_data(table, 1577916000, 1577926800).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+            assert!(error.get_ref().unwrap().downcast_ref::<InvalidName>().is_some());
+        }
+        let error = database.insert_record("../levels", MpdRecordType::new(1577916000, Vec::new())).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+        // Paths that could leave the database
+        assert_eq!(database.find_file("../Cargo.toml").unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(database.find_file("/levels/../../Cargo.toml").unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(database.delete_file("levels", "../../Cargo.toml").unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(database.delete_file("levels", "/tmp/file").unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(database.insert_at("..", "22", Entry{table: "levels".to_string(), data: Vec::new()}).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+
+        // A symlink inside the database pointing outside of it
+        #[cfg(unix)]
+        {
+            let outside = TempDatabase::new();
+            std::fs::create_dir_all(outside.path().join("20200101")).unwrap();
+            std::fs::write(outside.path().join("20200101/22"), b"").unwrap();
+            std::os::unix::fs::symlink(outside.path(), database.path().join("name_validation_link")).unwrap();
+            assert_eq!(database.find_file("name_validation_link/20200101/22").unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+            assert_eq!(database.delete_file("name_validation_link", "20200101/22").unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+            assert_eq!(database.get_data("name_validation_link", 1577916000, 1577919599).unwrap().try_next().unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+
+            // Writes are refused too, whether the hour file or only the table exists
+            for id in [1577916000, 1577923200].iter() {
+                let error = database.insert_record("name_validation_link", MpdRecordType::new(*id, Vec::new())).unwrap_err();
+                assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+            }
+            assert_eq!(std::fs::read(outside.path().join("20200101/22")).unwrap(), b"");
+            assert!(!outside.path().join("20200102").exists());
+
+            // So is an hour file linking outside of a real table
+            std::fs::create_dir_all(database.path().join("levels/20200101")).unwrap();
+            std::os::unix::fs::symlink(outside.path().join("20200101/22"), database.path().join("levels/20200101/22")).unwrap();
+            let error = database.insert_record("levels", MpdRecordType::new(1577916000, Vec::new())).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+            assert_eq!(std::fs::read(outside.path().join("20200101/22")).unwrap(), b"");
+            std::fs::remove_file(database.path().join("levels/20200101/22")).unwrap();
+        }
+
+        // Valid names still work
+        assert!(validate_table_name("levels_1h").is_ok());
+        assert!(validate_relative_path("20200101/22").is_ok());
+        assert!(database.get_data("levels", 1577916000, 1577926800).is_ok());
+
+        println!("Finished test_name_validation test!");
+    }
+}
+
+#[cfg(test)]
+mod cursor_range_tests {
+    use super::*;
+    use proptest::prelude::*;
+    use crate::fixtures::{ShardBuilder, TempDatabase};
+
+    /// 2020-01-01 00:00, the start of the generated days
+    const BASE: u32 = 1577836800;
+
+    /// Generated records span this many days
+    const DAYS: u32 = 3;
+
+    /// ids()
+    ///
+    /// Reads the ids a cursor returns
+    fn ids(database: &Database, start_ts: u32, end_ts: u32) -> Vec<u32> {
+        let mut cursor = database.get_data("levels", start_ts, end_ts).unwrap();
+        let mut record: Option<MpdRecordType> = None;
+        let mut ids = Vec::new();
+        loop {
+            cursor.next(&mut record);
+            match record.take() {
+                Some(entry) => ids.push(entry.id),
+                None => break
+            }
+        }
+        return ids;
+    }
+
+    /// Any second within the generated days, or an hour around them
+    fn timestamp() -> impl Strategy<Value = u32> {
+        (BASE - 3600)..(BASE + DAYS * 86400 + 3600)
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn test_cursor_range(
+            records in prop::collection::vec(BASE..(BASE + DAYS * 86400), 0..40),
+            empty_hours in prop::collection::vec(0..(DAYS * 24), 0..6),
+            start_ts in timestamp(),
+            end_ts in timestamp()
+        ) {
+            let database = TempDatabase::new();
+
+            // Empty hour files first, so records can still land in them
+            let mut shards = database.shards("levels");
+            for hour in &empty_hours {
+                let hour = get_datetime(BASE + hour * 3600);
+                shards = shards.empty(&hour.format(DATE_FORMAT).to_string(), &hour.format(TIME_FORMAT).to_string());
+            }
+            for id in &records {
+                shards = shards.at(*id);
+            }
+
+            let mut expected: Vec<u32> = records.iter().cloned().filter(|id| start_ts <= *id && *id <= end_ts).collect();
+            expected.sort();
+            prop_assert_eq!(ids(&database, start_ts, end_ts), expected);
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn test_cursor_resume(quarters in prop::collection::vec(0..(DAYS * 96), 1..40), split in any::<prop::sample::Index>()) {
+            let database = Database::in_memory();
+            let mut shards = ShardBuilder::new(&database, "levels");
+            for quarter in &quarters {
+                shards = shards.at(BASE + quarter * 900);  // Some ids repeat
+            }
+            let all = ids(&database, BASE, u32::MAX);
+
+            // Stop anywhere, then resume from the continuation
+            let split = split.index(all.len()) + 1;
+            let mut cursor = database.get_data("levels", BASE, u32::MAX).unwrap();
+            let mut resumed = Vec::new();
+            for _ in 0..split {
+                resumed.push(cursor.try_next().unwrap().unwrap().id);
+            }
+            let mut cursor = database.resume(&cursor.continuation().unwrap(), u32::MAX).unwrap();
+            while let Some(record) = cursor.try_next().unwrap() {
+                resumed.push(record.id);
+            }
+            prop_assert_eq!(resumed, all);
+        }
+    }
+
+    #[test]
+    fn test_cursor_resume_appended() {
+        let database = Database::in_memory();
+        ShardBuilder::new(&database, "levels").at(BASE + 60).at(BASE + 60).at(BASE + 120);
+        let mut cursor = database.get_data("levels", BASE, BASE + 3599).unwrap();
+        cursor.try_next().unwrap();
+        cursor.try_next().unwrap();
+        let after = cursor.continuation().unwrap();
+        assert_eq!((after.table.as_str(), after.shard, after.last_id), ("levels", BASE, BASE + 60));
+
+        // A late record sorting before the continuation stays behind, one after it is returned
+        ShardBuilder::new(&database, "levels").at(BASE + 60).at(BASE);
+        let mut cursor = database.resume(&after, BASE + 3599).unwrap();
+        let mut ids = Vec::new();
+        while let Some(record) = cursor.try_next().unwrap() {
+            ids.push(record.id);
+        }
+        assert_eq!(ids, vec![BASE + 60, BASE + 120]);
+        assert!(database.get_data("levels", BASE, BASE).unwrap().continuation().is_none());
+    }
+
+    #[test]
+    fn test_cursor_range_limits() {
+        let database = Database::in_memory();
+        ShardBuilder::new(&database, "levels").at(0).at(BASE).at(u32::MAX);
+
+        // Ranges touching the ends of the timestamps
+        assert_eq!(ids(&database, 0, 10), vec![0]);
+        assert_eq!(ids(&database, 1, BASE), vec![BASE]);
+        assert_eq!(ids(&database, BASE, u32::MAX), vec![BASE, u32::MAX]);
+
+        // A record an hour before the start isn't returned
+        assert_eq!(ids(&database, BASE + 1800, BASE + 3600), Vec::<u32>::new());
+        assert_eq!(ids(&database, BASE + 1, BASE), Vec::<u32>::new());
+    }
+}
+
+#[cfg(test)]
+mod decode_tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// 2020-01-01 00:00
+    const HOUR: u32 = 1577836800;
+
+    /// read_file()
+    ///
+    /// Reads an hour file made of 'bytes' to the end, returning its records
+    /// and errors. Fails if the cursor doesn't finish.
+    fn read_file(bytes: &[u8]) -> (Vec<MpdRecordType>, Vec<CorruptFile>) {
+        let database = Database::in_memory();
+        database.storage().append("levels", &get_datetime(HOUR), bytes).unwrap();
+
+        let mut cursor = database.get_data("levels", 0, u32::MAX).unwrap();
+        let mut records = Vec::new();
+        let mut errors = Vec::new();
+        // Every record takes at least 4 bytes, then one error for the file and the end
+        for _ in 0..bytes.len() / 4 + 2 {
+            match cursor.try_next() {
+                Ok(Some(record)) => records.push(record),
+                Ok(None) => return (records, errors),
+                Err(error) => {
+                    assert_eq!(error.kind(), ErrorKind::InvalidData);
+                    errors.push(error.get_ref().unwrap().downcast_ref::<CorruptFile>().unwrap().clone());
+                }
+            }
+        }
+        panic!("Cursor didn't finish reading {} bytes", bytes.len());
+    }
+
+    /// valid_file()
+    ///
+    /// An hour file of 'count' records
+    fn valid_file(count: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for i in 0..count {
+            bytes.extend(serialize_struct(MpdRecordType::new(HOUR + i, new_buf().unwrap())).unwrap());
+        }
+        return bytes;
+    }
+
+    #[test]
+    fn test_decode_corrupt_files() {
+        let bytes = valid_file(3);
+        let (records, errors) = read_file(&bytes);
+        assert_eq!(records.len(), 3);
+        assert!(errors.is_empty());
+
+        // A partial write keeps the records before it
+        let (records, errors) = read_file(&bytes[..bytes.len() - 1]);
+        assert_eq!(records.len(), 2);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].table, "levels");
+        assert_eq!(errors[0].hour, get_datetime(HOUR));
+        assert!(errors[0].offset > 0);
+
+        // A record with a wrong checksum is left out, the rest is read
+        let mut record = MpdRecordType::new(HOUR, vec![1, 2, 3]);
+        record.checksum += 1;
+        let mut bytes = serialize_struct(record).unwrap();
+        bytes.extend(valid_file(1));
+        let (records, errors) = read_file(&bytes);
+        assert_eq!(records.len(), 1);
+        assert_eq!(errors[0].offset, 0);
+
+        // Lengths past the end of the file and deep nesting
+        let (records, errors) = read_file(&[0x93, 0x00, 0xc6, 0xff, 0xff, 0xff, 0xff]);
+        assert!(records.is_empty());
+        assert_eq!(errors.len(), 1);
+        let (_, errors) = read_file(&[0xdd, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(errors.len(), 1);
+        let (_, errors) = read_file(&vec![0x91; 100000]);
+        assert_eq!(errors.len(), 1);
+
+        // The old cursor interface skips corruption
+        let database = Database::in_memory();
+        database.storage().append("levels", &get_datetime(HOUR), &[0xc1]).unwrap();
+        database.insert_record("levels", MpdRecordType::new(HOUR + 3600, Vec::new())).unwrap();
+        let mut cursor = database.get_data("levels", HOUR, HOUR + 3600).unwrap();
+        let mut record: Option<MpdRecordType> = None;
+        cursor.next(&mut record);
+        assert_eq!(record.map(|entry| entry.id), Some(HOUR + 3600));
+    }
+
+    proptest! {
+        #[test]
+        fn test_decode_arbitrary_bytes(bytes in prop::collection::vec(any::<u8>(), 0..512)) {
+            let (records, _) = read_file(&bytes);
+            for record in records {
+                prop_assert_eq!(record.checksum, crc32::checksum_ieee(&record.datalog));
+            }
+        }
+
+        #[test]
+        fn test_decode_damaged_file(count in 1..6u32, damage in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..4), cut in any::<prop::sample::Index>()) {
+            let mut bytes = valid_file(count);
+            for (index, byte) in damage {
+                let index = index.index(bytes.len());
+                bytes[index] = byte;
+            }
+            let cut = cut.index(bytes.len() + 1);
+            let (records, errors) = read_file(&bytes[..cut]);
+            prop_assert!(records.len() <= count as usize);
+            prop_assert!(errors.len() <= 1);
+        }
+    }
+}
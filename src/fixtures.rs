@@ -0,0 +1,217 @@
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use chrono::prelude::*;
+use rustls::internal::pemfile;
+use rustls::{AllowAnyAuthenticatedClient, NoClientAuth, RootCertStore, ServerConfig, ServerSession, Session};
+use tempfile::TempDir;
+
+use crate::database::{self, Database, MpdRecordType};
+
+/// TempDatabase
+///
+/// A database in its own temporary directory, removed when dropped,
+/// so tests don't share files
+pub struct TempDatabase {
+    database:   Database,
+    dir:        TempDir,
+}
+
+/// ShardBuilder
+///
+/// Writes synthetic hour files for a table
+pub struct ShardBuilder<'a> {
+    database:   &'a Database,
+    table:      String,
+}
+
+impl TempDatabase {
+    /// Constructor
+    pub fn new() -> TempDatabase {
+        let dir = tempfile::Builder::new().prefix("local_storage").tempdir().unwrap();
+        let database = Database::new(dir.path()).unwrap();
+        TempDatabase {
+            database,
+            dir
+        }
+    }
+
+    /// path()
+    ///
+    /// Returns the temporary root of the database
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// shards()
+    ///
+    /// Starts building hour files for a table
+    pub fn shards(&self, table: &str) -> ShardBuilder<'_> {
+        ShardBuilder::new(&self.database, table)
+    }
+}
+
+impl Deref for TempDatabase {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        &self.database
+    }
+}
+
+impl<'a> ShardBuilder<'a> {
+    /// Constructor
+    pub fn new(database: &'a Database, table: &str) -> ShardBuilder<'a> {
+        ShardBuilder {
+            database,
+            table: table.to_string()
+        }
+    }
+
+    /// records()
+    ///
+    /// Appends 'count' random records to an hour file, one second apart
+    /// from the start of the hour, e.g. ("20200101", "22", 2)
+    pub fn records(self, date: &str, hour: &str, count: u32) -> Self {
+        let start = hour_of(date, hour).timestamp() as u32;
+        for i in 0..count {
+            self.record(start + i)
+        }
+        self
+    }
+
+    /// at()
+    ///
+    /// Appends a random record with the given id (timestamp)
+    pub fn at(self, id: u32) -> Self {
+        self.record(id);
+        self
+    }
+
+    /// empty()
+    ///
+    /// Creates an hour file without any records
+    pub fn empty(self, date: &str, hour: &str) -> Self {
+        self.database.storage().append(&self.table, &hour_of(date, hour), &[]).unwrap();
+        self
+    }
+
+    fn record(&self, id: u32) {
+        self.database.insert_record(&self.table, MpdRecordType::new(id, database::new_buf().unwrap())).unwrap();
+    }
+}
+
+/// count()
+///
+/// Counts the records a cursor returns over a table
+pub fn count(database: &Database, table: &str, start_ts: u32, end_ts: u32) -> usize {
+    let mut cursor = database.get_data(table, start_ts, end_ts).unwrap();
+    let mut record: Option<MpdRecordType> = None;
+    let mut count = 0;
+    loop {
+        cursor.next(&mut record);
+        if record.is_none() { break; }
+        count += 1;
+    }
+    return count;
+}
+
+/// hour_of()
+///
+/// Parses a date ("YYYYmmdd") and hour ("HH") into a datetime
+fn hour_of(date: &str, hour: &str) -> DateTime<Utc> {
+    crate::storage::parse_shard(date, hour).unwrap()
+}
+
+/// Certificates for TLS tests, made by `tests/certs/generate.sh`
+pub const CERTS: &str = "tests/certs";
+
+/// TlsBroker
+///
+/// Stands in for a TLS-enabled MQTT broker on localhost. It accepts one
+/// connection, answers its CONNECT and reports the client id.
+pub struct TlsBroker {
+    pub port:       u16,
+    pub clients:    mpsc::Receiver<Result<String, io::Error>>,
+}
+
+impl TlsBroker {
+    /// Constructor
+    ///
+    /// Starts listening, requiring a client certificate signed by the test CA if 'client_auth'
+    pub fn start(client_auth: bool) -> TlsBroker {
+        let mut config = match client_auth {
+            true => {
+                let mut roots = RootCertStore::empty();
+                roots.add_pem_file(&mut BufReader::new(fs::File::open(format!("{}/ca.pem", CERTS)).unwrap())).unwrap();
+                ServerConfig::new(AllowAnyAuthenticatedClient::new(roots))
+            },
+            false => ServerConfig::new(NoClientAuth::new())
+        };
+        let certs = pemfile::certs(&mut &fs::read(format!("{}/server.pem", CERTS)).unwrap()[..]).unwrap();
+        let key = pemfile::rsa_private_keys(&mut &fs::read(format!("{}/server.key", CERTS)).unwrap()[..]).unwrap().remove(0);
+        config.set_single_cert(certs, key).unwrap();
+        let config = Arc::new(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, clients) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut tcp, _) = listener.accept().unwrap();
+            let mut session = ServerSession::new(&config);
+            let client_id = accept_connect(&mut rustls::Stream::new(&mut session, &mut tcp));
+            let connected = client_id.is_ok();
+            let _ = sender.send(client_id);
+
+            // Stay connected until the client leaves
+            if connected {
+                let mut buf = [0; 1024];
+                while let Ok(read) = rustls::Stream::new(&mut session, &mut tcp).read(&mut buf) {
+                    if read == 0 { break; }
+                }
+            }
+            let _ = session.write_tls(&mut tcp);
+        });
+        TlsBroker {
+            port,
+            clients
+        }
+    }
+}
+
+/// accept_connect()
+///
+/// Reads an MQTT CONNECT packet, answers it with a CONNACK and returns the client id
+fn accept_connect<S: Read + Write>(stream: &mut S) -> Result<String, io::Error> {
+    let mut byte = [0; 1];
+    stream.read_exact(&mut byte)?;
+    if byte[0] != 0x10 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a CONNECT packet"));
+    }
+
+    // Remaining length, 7 bits per byte
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        stream.read_exact(&mut byte)?;
+        length |= usize::from(byte[0] & 0x7f) << shift;
+        shift += 7;
+        if byte[0] & 0x80 == 0 { break; }
+    }
+    let mut packet = vec![0; length];
+    stream.read_exact(&mut packet)?;
+
+    // Protocol name, level, flags and keep-alive come before the client id
+    let name_length = usize::from(packet[0]) << 8 | usize::from(packet[1]);
+    let id_start = 2 + name_length + 4;
+    let id_length = usize::from(packet[id_start]) << 8 | usize::from(packet[id_start + 1]);
+    let client_id = String::from_utf8_lossy(&packet[id_start + 2..id_start + 2 + id_length]).to_string();
+
+    stream.write_all(&[0x20, 0x02, 0x00, 0x00])?;
+    stream.flush()?;
+    return Ok(client_id);
+}
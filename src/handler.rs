@@ -0,0 +1,1304 @@
+use crate::database::{self, Continuation, Database, MpdRecordType, MyCursor};
+use crate::parser;
+use crate::query;
+use crate::transport::{Backoff, Incoming, Message, MqttTransport, Transport};
+use crate::writer::Writer;
+
+use log::{error, info, warn, debug};
+
+use rumqtt::{LastWill, MqttOptions, QoS, SecurityOptions};
+use rustls::internal::pemfile;
+
+use std::str;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{thread, time};
+use std::fs;
+use std::io::{Error, ErrorKind};
+
+
+use serde::{Serialize, Deserialize};
+use rmps::Deserializer;
+
+/// Retained on the status topic while running, and by the broker's last will once not
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+
+/// Clients acknowledge chunks on this topic
+pub const ACK_TOPIC: &str = "topic_ack";
+
+/// STATE is used by 'initialize_handler()' which set the interrupt handler
+static mut STATE: CurrentState = CurrentState::Available;
+
+/// CurrentState
+/// 
+/// Is used to determine the current state of
+/// the program for interrupts like 'ctrl-c'
+enum CurrentState {
+    Available,
+    Busy,
+}
+
+/// MQTT Structs.
+///
+/// These are structs associated with MQTT.
+/// They will be used mostly for deserialization/serialization
+/// before receiving/sending
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GetData {
+    pub table:      String,
+    pub start_ts:   u32,
+    pub end_ts:     u32,
+    #[serde(default)]
+    pub window:     u32,    // Chunks sent ahead of the client's acknowledgements, 0 to not wait for any
+    #[serde(default)]
+    pub after:      Option<Continuation>,   // Resume after the last record of an earlier reply
+    #[serde(default)]
+    pub limit:      u32,    // Most records to send, 0 for all of them
+    #[serde(default)]
+    pub offset:     u32,    // Records to skip first
+    #[serde(default)]
+    pub fields:     Vec<String>,    // Only send these fields of each datalog, all of it if empty
+    #[serde(default)]
+    pub filter:     Option<String>, // Only send records matching this expression, see `query::Filter`
+    #[serde(default)]
+    pub bucket_secs: u32    // Send a `query::Bucket` of the fields per this many seconds instead of records, 0 for records
+}
+
+/// Rebuilds a rollup table from its source between 'start_ts' and 'end_ts'
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Backfill {
+    pub table:      String,
+    pub start_ts:   u32,
+    pub end_ts:     u32
+}
+
+/// A part of a get_data reply. Chunks are numbered from 0 and the
+/// stream ends with an empty message. 'next' resumes after the chunk's
+/// last record when sent back as 'after'.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Chunk {
+    pub seq:        u32,
+    pub records:    Vec<MpdRecordType>,
+    pub next:       Continuation,
+    pub more:       bool    // Records of the range remain after this chunk, also past the limit
+}
+
+/// Source
+///
+/// Where the records of a get_data reply come from
+enum Source {
+    Records(MyCursor, Vec<String>),     // Records, with only these fields if any
+    Buckets(query::Aggregator),         // A record per time bucket
+}
+
+impl Source {
+    /// next()
+    ///
+    /// Returns the next record of the reply, None once there is nothing more,
+    /// or an error for an hour file that couldn't be read
+    fn next(&mut self) -> Result<Option<MpdRecordType>, Error> {
+        match self {
+            Source::Records(cursor, fields) => next_record(cursor, fields),
+            Source::Buckets(aggregator) => loop {
+                let bucket = match aggregator.next() {
+                    Some(bucket) => bucket,
+                    None => return Ok(None)
+                };
+                match bucket.to_record() {
+                    Ok(record) => return Ok(Some(record)),
+                    Err(error) => error!("Could not encode bucket {}! {:?}", bucket.start, error)
+                }
+            }
+        }
+    }
+
+    /// continuation()
+    ///
+    /// Returns where the reply can be resumed after the last record
+    fn continuation(&self) -> Option<Continuation> {
+        match self {
+            Source::Records(cursor, _) => cursor.continuation(),
+            Source::Buckets(aggregator) => aggregator.continuation()
+        }
+    }
+}
+
+/// Sent by the client on `ACK_TOPIC` once it has every chunk up to 'seq'
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Ack {
+    pub seq:        u32
+}
+
+/// Sent instead of the rest of a get_data stream that couldn't be finished,
+/// followed by the usual empty terminator. It is a map, so it can be told
+/// apart from records which are arrays.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ErrorEnvelope {
+    pub error:      String,
+    pub table:      String,
+    pub start_ts:   u32,
+    pub end_ts:     u32
+}
+
+/// Published on `<status topic>/heartbeat` while running
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Heartbeat {
+    pub uptime_secs:    u64,
+    pub database_bytes: u64,
+    pub last_ingest:    Option<i64>     // Unix time a record was last written
+}
+
+/// Status
+///
+/// Where serve() reports that it's running, and how often it sends a heartbeat
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub topic:      String,
+    pub heartbeat:  time::Duration,
+}
+
+/// handler()
+///
+/// Connects to MQTT and serves requests until the notifications end or ctrl-c is used
+pub fn handler(config: parser::Config) {
+    // Initialize Variables
+    if let Err(error) = config.validate() {
+        error!("{}", error);
+        return
+    }
+    let mqtt_options = match mqtt_options(&config) {
+        Ok(mqtt_options) => mqtt_options,
+        Err(error) => {
+            error!("Could not set up the MQTT connection! {}", error);
+            return
+        }
+    };
+    let database = match Database::new("data") {
+        Ok(database) => database,
+        Err(error) => {
+            error!("Could not open the database! {:?}", error);
+            return
+        }
+    };
+    let writer = match Writer::new(database.clone()) {
+        Ok(writer) => Arc::new(Mutex::new(writer)),
+        Err(error) => {
+            error!("Could not create the writer! {:?}", error);
+            return
+        }
+    };
+    {
+        let mut writer = writer.lock().unwrap();
+        writer.set_max_buffered(config.writer.max_buffered);
+        writer.set_flush_interval(time::Duration::from_millis(config.writer.flush_interval_ms));
+        writer.set_lateness(time::Duration::from_secs(config.writer.lateness_secs));
+        for table in &config.writer.dedup_tables {
+            writer.set_dedup(table, true);
+        }
+        for rollup in &config.rollups {
+            match rollup.rollup() {
+                Ok(rollup) => writer.add_rollup(rollup),
+                Err(error) => error!("{}", error)
+            }
+        }
+    }
+
+    // Measure the database once, heartbeats report the size kept up to date by the storage
+    match database.storage().size() {
+        Ok(size) => info!("The database holds {} bytes", size),
+        Err(error) => error!("Could not get the size of the database! {:?}", error)
+    }
+
+    // Set up ctrl-c handler
+    let running = initialize_handler(writer.clone());
+
+    // Keep trying until the broker can be reached, waiting longer every time
+    let mut backoff = Backoff::new(time::Duration::from_secs(config.reconnect_secs), time::Duration::from_secs(config.reconnect_max_secs));
+    let transport = loop {
+        match MqttTransport::connect(mqtt_options.clone(), backoff.clone()) {
+            Ok(transport) => break transport,
+            Err(error) => {
+                let delay = backoff.next_delay();
+                warn!("Could not connect to MQTT, retrying in {:?}! {:?}", delay, error);
+                thread::sleep(delay);
+            }
+        }
+    };
+
+    let status = Status {
+        topic:      config.status_topic,
+        heartbeat:  time::Duration::from_secs(config.heartbeat_secs)
+    };
+    serve(transport, &database, &writer, config.topics, &status, &config.stream, &running);
+}
+
+/// mqtt_options()
+///
+/// Builds the MQTT connection settings of a validated config, reading
+/// the TLS certificates and key if there are any
+pub fn mqtt_options(config: &parser::Config) -> Result<MqttOptions, Error> {
+    let mut mqtt_options = MqttOptions::new(config.client_id.clone(), config.ip.clone(), config.port as u16)
+        .set_keep_alive(config.keep_alive_secs)
+        .set_clean_session(config.clean_session)
+        .set_last_will(LastWill {
+            topic:      config.status_topic.clone(),
+            message:    OFFLINE.to_string(),
+            qos:        QoS::AtLeastOnce,
+            retain:     true
+        });
+    if let Some(username) = &config.username {
+        let password = config.password.clone().unwrap_or_default();
+        mqtt_options = mqtt_options.set_security_opts(SecurityOptions::UsernamePassword(username.clone(), password));
+    }
+    if let Some(ca_file) = &config.ca_file {
+        mqtt_options = mqtt_options.set_ca(read_certificates(ca_file)?);
+        if let (Some(cert_file), Some(key_file)) = (&config.client_cert_file, &config.client_key_file) {
+            mqtt_options = mqtt_options.set_client_auth(read_certificates(cert_file)?, read_private_key(key_file)?);
+        }
+    }
+    return Ok(mqtt_options);
+}
+
+/// read_certificates()
+///
+/// Reads a PEM file, checking it has certificates
+fn read_certificates(path: &str) -> Result<Vec<u8>, Error> {
+    let pem = fs::read(path).map_err(|error| Error::new(error.kind(), format!("Could not read {:?}: {}", path, error)))?;
+    match pemfile::certs(&mut &pem[..]) {
+        Ok(certificates) if !certificates.is_empty() => return Ok(pem),
+        _ => return Err(Error::new(ErrorKind::InvalidData, format!("{:?} has no PEM certificates", path)))
+    }
+}
+
+/// read_private_key()
+///
+/// Reads a PEM file, checking it has an RSA (PKCS#1) key, the only kind rumqtt reads
+fn read_private_key(path: &str) -> Result<Vec<u8>, Error> {
+    let pem = fs::read(path).map_err(|error| Error::new(error.kind(), format!("Could not read {:?}: {}", path, error)))?;
+    match pemfile::rsa_private_keys(&mut &pem[..]) {
+        Ok(keys) if !keys.is_empty() => return Ok(pem),
+        _ => return Err(Error::new(ErrorKind::InvalidData, format!("{:?} has no PEM RSA private key (BEGIN RSA PRIVATE KEY)", path)))
+    }
+}
+
+/// serve()
+///
+/// Subscribes to the topics and serves requests until the transport
+/// disconnects or 'running' is cleared. The status is online meanwhile.
+pub fn serve<T: Transport>(mut transport: T, database: &Database, writer: &Mutex<Writer>, topics: Vec<String>, status: &Status, stream: &parser::StreamConfig, running: &AtomicBool) {
+    let flush_interval = writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).flush_interval();
+    let started = time::Instant::now();
+    let mut last_heartbeat = started;
+
+    // Let clients know requests are served
+    set_status(&mut transport, status, ONLINE);
+
+    /*** 3 STEPS TO GET NOTIFICATIONS FROM SUBSCRIBED TOPIC ***/
+    // Topics (Step 1 in adding command) come from parser.rs which gets topics from toml file
+
+    // Subscribe to servers to receive publishes (Step 2 in adding command)
+    subscribe(&mut transport, &topics);
+
+    // A get_data stream cut off by a lost connection, answered once reconnected
+    let mut interrupted: Option<GetData> = None;
+
+    // Received while waiting for acknowledgements, handled before anything new
+    let mut deferred: VecDeque<Incoming> = VecDeque::new();
+
+    // Parse notifications
+    loop {
+        // Wake up at least once per flush interval to write buffered records, and for heartbeats
+        let notification = match deferred.pop_front() {
+            Some(notification) => notification,
+            None => transport.receive(std::cmp::min(flush_interval, status.heartbeat))
+        };
+        let notification = match notification {
+            Incoming::Timeout => {
+                flush_writer(writer, false);
+                heartbeat(&mut transport, status, database, writer, started, &mut last_heartbeat);
+                continue;
+            },
+            Incoming::Disconnected => break,
+            notification => notification
+        };
+
+        // Change to Busy
+        change_state();
+
+        match notification {
+            Incoming::Publish(Message{topic, payload}) =>  {
+                    // Match topics of notification (Step 3 in adding command)
+                    // Note, this has to be manually inputted at the moment as results are different for topics
+                    match topic {
+                        topic if &topic == "topic1" => debug!("{:?}", topic), // Random topic
+                        topic if &topic == "topic2" => debug!("{:?}", topic), // Random topic
+                        topic if &topic == "topic3" => debug!("{:?}", topic), // Random topic
+                        topic if &topic == "topic_add" => add(payload, writer).unwrap(), // Add data to DB
+                        topic if &topic == "topic_delete" => delete(database, writer).unwrap(), // Delete data from DB
+                        topic if &topic == "topic_backfill" => {
+                            match backfill(&payload, writer) {
+                                Ok(buckets) => info!("Backfilled {} buckets.", buckets),
+                                Err(error) => error!("Could not backfill! {:?}", error)
+                            }
+                        },
+                        topic if &topic == "topic_getdata" => {
+                            let mut de = Deserializer::new(&payload[..]);
+                            match GetData::deserialize(&mut de) {
+                                Ok(data) => {
+                                    match get_data(&data, database, &mut transport, &topic, stream, &mut deferred) {
+                                        Ok(_) => info!("Successfully sent data."),
+                                        Err(error) => {
+                                            error!("There was an Error! {:?}", error);
+                                            if !transport.is_connected() {
+                                                interrupted = Some(data);
+                                            } else if error.kind() == ErrorKind::TimedOut {
+                                                abort_stream(&mut transport, &data, "Chunks were not acknowledged in time, request the rest again");
+                                            } else {
+                                                // e.g. an invalid filter or table name, or a corrupt hour file, the client is told why
+                                                abort_stream(&mut transport, &data, &error.to_string());
+                                            }
+                                        }
+                                    }
+                                },
+                                Err(error) => {
+                                    // Not even a table to answer for, the envelope is left empty
+                                    error!("Invalid get_data request! {:?}", error);
+                                    abort_stream(&mut transport, &GetData::default(), &format!("Invalid get_data request: {}", error));
+                                }
+                            }
+                        },
+                        topic if topic == ACK_TOPIC => debug!("Acknowledgement outside of a stream, ignoring"),
+                        _ => error!("Invalid Topic!") // Throw an error
+                    }
+                },
+            Incoming::ConnectionLost => warn!("Lost the connection to the broker, reconnecting."),
+            Incoming::Reconnected => {
+                info!("Reconnected to the broker.");
+                subscribe(&mut transport, &topics);
+                set_status(&mut transport, status, ONLINE);
+                if let Some(data) = interrupted.take() {
+                    abort_stream(&mut transport, &data, "Stream interrupted by a lost broker connection, request it again");
+                }
+            },
+            _ => warn!("Received something that's not a publish! {:?}. Ignoring...", notification)
+        }
+
+        // Write buffered records that have waited too long
+        flush_writer(writer, false);
+        heartbeat(&mut transport, status, database, writer, started, &mut last_heartbeat);
+
+        // Change to Available
+        change_state();
+
+        // Check to see if ctrl-c was used
+        if !running.load(Ordering::SeqCst) {
+            info!("Shutting down.");
+            break;
+        }
+    }
+
+    // Write everything that is still buffered
+    flush_writer(writer, true);
+
+    // A clean disconnect doesn't trigger the last will
+    set_status(&mut transport, status, OFFLINE);
+}
+
+/// set_status()
+///
+/// Retains the status for clients subscribing later
+fn set_status<T: Transport>(transport: &mut T, status: &Status, state: &str) {
+    if let Err(error) = transport.publish_retained(&status.topic, state.as_bytes().to_vec()) {
+        error!("Could not publish the status {:?}! {:?}", state, error);
+    }
+}
+
+/// heartbeat()
+///
+/// Publishes a heartbeat if the last one is older than the heartbeat interval
+fn heartbeat<T: Transport>(transport: &mut T, status: &Status, database: &Database, writer: &Mutex<Writer>, started: time::Instant, last_heartbeat: &mut time::Instant) {
+    if last_heartbeat.elapsed() < status.heartbeat {
+        return;
+    }
+    *last_heartbeat = time::Instant::now();
+
+    let database_bytes = match database.storage().size() {
+        Ok(size) => size,
+        Err(error) => {
+            error!("Could not get the size of the database! {:?}", error);
+            0
+        }
+    };
+    let heartbeat = Heartbeat {
+        uptime_secs:    started.elapsed().as_secs(),
+        database_bytes,
+        last_ingest:    writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).last_ingest().map(|time| time.timestamp())
+    };
+    let result = rmps::to_vec_named(&heartbeat)
+        .map_err(|error| Error::new(ErrorKind::InvalidData, error))
+        .and_then(|buf| transport.publish(&format!("{}/heartbeat", status.topic), buf));
+    if let Err(error) = result {
+        error!("Could not publish a heartbeat! {:?}", error);
+    }
+}
+
+
+/// flush_writer()
+/// 
+/// Writes buffered records to disk, either all of them or only those that are due
+fn flush_writer(writer: &Mutex<Writer>, all: bool) {
+    let mut writer = writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let result = match all {
+        true => writer.flush(),
+        false => writer.flush_if_due()
+    };
+    if let Err(error) = result {
+        error!("Could not write buffered records! {:?}", error);
+    }
+}
+
+/// add()
+/// 
+/// add data to DB (not to be used by clients, only for testing)
+#[allow(unused_assignments)]
+fn add(payload: Vec<u8>, writer: &Mutex<Writer>) -> Result<(), Error> {
+    // Deserialize payload
+    let mut de = Deserializer::new(&payload[..]);
+    let result: String = Deserialize::deserialize(&mut de).unwrap();
+    let amount = result.trim().parse::<u32>().unwrap();
+
+    let mut writer = writer.lock().unwrap();
+    let mut buf: Vec<u8> = Vec::new();
+    for _ in 0..amount {
+        buf = database::new_buf().unwrap();
+        writer.write(1577923200, database::Entry{table: "levels".to_string(), data: buf})?;  // 20200102/00
+    }
+    info!("Stats for levels: {:?}", writer.stats("levels"));
+
+    Ok(())
+}
+
+/// delete()
+/// 
+/// Deletes data from DB (not to be used by clients, only for testing)
+fn delete(database: &Database, writer: &Mutex<Writer>) -> Result<(), Error> {
+    // Close the hour file before removing it
+    writer.lock().unwrap().close()?;
+    database.delete_file("levels", "20200102/00").unwrap();
+    Ok(())
+}
+
+/// backfill()
+///
+/// Rebuilds the rollup table of a Backfill request, returning the buckets written
+fn backfill(payload: &[u8], writer: &Mutex<Writer>) -> Result<usize, Error> {
+    let mut de = Deserializer::new(payload);
+    let request = Backfill::deserialize(&mut de).map_err(|error| Error::new(ErrorKind::InvalidInput, error))?;
+    debug!("Backfill: {:?}", request);
+
+    let mut writer = writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    writer.backfill(&request.table, request.start_ts, request.end_ts)
+}
+
+/// change_state()
+/// 
+/// Swaps the current state
+fn change_state() {
+    unsafe {
+        match STATE {
+            CurrentState::Available => STATE = CurrentState::Busy,
+            _ => STATE = CurrentState::Available
+        };
+    }
+}
+
+/// initialize_handler()
+/// 
+/// Initializes the ctrl-c handler 
+fn initialize_handler(writer: Arc<Mutex<Writer>>) -> std::sync::Arc<AtomicBool> {
+    // Initialize signal handler for ctrl-c
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+
+    ctrlc::set_handler(move || {
+        unsafe {
+            match STATE {
+                CurrentState::Available => {
+                    flush_writer(&writer, true);
+                    info!("Ending the program.\n");
+                    std::process::exit(0);
+                },
+                _ => { 
+                    info!("Getting ready to shut down");
+                    r.store(false, Ordering::SeqCst);
+                }
+            }
+        }
+    }).expect("Error setting Ctrl-C handler");
+
+    return running;
+}
+
+/// get_data()
+/// 
+/// Grabs data from the database given the payload from MQTT
+fn get_data<T: Transport>(data: &GetData, database: &Database, transport: &mut T, topic: &str, stream: &parser::StreamConfig, deferred: &mut VecDeque<Incoming>) -> Result<(), Error> {
+    info!("Starting get_data()");
+    debug!("Request: {:?}", data);
+
+    debug!("Getting Cursor!");
+    let mut cursor = match &data.after {
+        Some(after) if after.table != data.table => return Err(Error::new(ErrorKind::InvalidInput, "The continuation is for another table")),
+        Some(after) => database.resume(after, data.end_ts)?,
+        None => database.get_data(&data.table, data.start_ts, data.end_ts)?
+    };
+    if let Some(filter) = &data.filter {
+        cursor.set_filter(query::Filter::parse(filter)?);
+    }
+    let mut source = match data.bucket_secs {
+        0 => Source::Records(cursor, data.fields.clone()),
+        bucket_secs => Source::Buckets(query::Aggregator::new(cursor, bucket_secs, data.fields.clone())?)
+    };
+
+    // Set Variables
+    let mut next: Option<Continuation> = None;  // After the last record of the chunk
+    let mut seq: u32 = 0;       // Chunks sent
+    let mut acked: u32 = 0;     // Chunks the client has
+    let mut records: Vec<MpdRecordType> = Vec::new();
+    let mut bytes = 0;
+    let mut record: Option<MpdRecordType> = None;
+    let mut taken: u32 = 0;     // Records put in chunks
+    let mut remaining = false;  // Records left past the limit
+
+    // Skip the offset
+    for _ in 0..data.offset {
+        if source.next()?.is_none() { break; }
+    }
+
+    debug!("Looping!");
+    loop {
+        if data.limit > 0 && taken >= data.limit {
+            // Only check whether anything is left past the limit
+            remaining = source.next()?.is_some();
+        } else {
+            record = source.next()?;
+        }
+        let length = match &record {
+            Some(record) => database::serialize_struct(record)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Could not serialize record"))?
+                .len(),
+            None => 0
+        };
+
+        // Send the chunk once it is full or nothing is left to read
+        let full = records.len() >= stream.chunk_records
+            || (stream.chunk_bytes > 0 && bytes + length > stream.chunk_bytes);
+        if !records.is_empty() && (full || record.is_none()) {
+            // Pause while the client is a whole window behind
+            if data.window > 0 && seq >= data.window {
+                wait_for_acks(transport, stream, deferred, &mut acked, seq + 1 - data.window)?;
+            }
+            let chunk = Chunk {
+                seq,
+                records: std::mem::take(&mut records),
+                next: next.take().ok_or_else(|| Error::other("No continuation for the chunk"))?,
+                more: record.is_some() || remaining
+            };
+            let buf = rmps::to_vec(&chunk).map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+            publish(transport, topic, buf)?;
+            seq += 1;
+            bytes = 0;
+        }
+
+        match record.take() {
+            Some(record) => {
+                records.push(record);
+                bytes += length;
+                taken += 1;
+                next = source.continuation();
+            },
+            None => {
+                // The terminator goes last, once the client has every chunk
+                if data.window > 0 {
+                    wait_for_acks(transport, stream, deferred, &mut acked, seq)?;
+                }
+                // Publish nothing to indicate there is no more data left
+                publish(transport, topic, Vec::new())?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// next_record()
+///
+/// Returns the next record of the cursor with its datalog replaced by a map
+/// of only the requested fields, if any. Records whose datalog can't be
+/// decoded are left out, while a corrupt hour file is an error.
+fn next_record(cursor: &mut MyCursor, fields: &[String]) -> Result<Option<MpdRecordType>, Error> {
+    loop {
+        let record = match cursor.try_next()? {
+            Some(record) => record,
+            None => return Ok(None)
+        };
+        if fields.is_empty() {
+            return Ok(Some(record));
+        }
+        match query::project(&record.datalog, fields) {
+            Ok(datalog) => return Ok(Some(MpdRecordType::new(record.id, datalog))),
+            Err(error) => error!("Could not project record {}! {:?}", record.id, error)
+        }
+    }
+}
+
+/// wait_for_acks()
+///
+/// Receives until the client acknowledged 'target' chunks. Everything
+/// else received meanwhile is kept in 'deferred' for serve().
+fn wait_for_acks<T: Transport>(transport: &mut T, stream: &parser::StreamConfig, deferred: &mut VecDeque<Incoming>, acked: &mut u32, target: u32) -> Result<(), Error> {
+    let timeout = time::Duration::from_millis(stream.ack_timeout_ms);
+    let started = time::Instant::now();
+    while *acked < target {
+        let remaining = match timeout.checked_sub(started.elapsed()) {
+            Some(remaining) => remaining,
+            None => return Err(Error::new(ErrorKind::TimedOut, "The client stopped acknowledging chunks"))
+        };
+        match transport.receive(remaining) {
+            Incoming::Publish(Message{topic, payload}) if topic == ACK_TOPIC => {
+                match rmps::from_slice::<Ack>(&payload) {
+                    Ok(ack) => *acked = std::cmp::max(*acked, ack.seq.saturating_add(1)),
+                    Err(error) => warn!("Invalid acknowledgement! {:?}", error)
+                }
+            },
+            Incoming::Timeout => return Err(Error::new(ErrorKind::TimedOut, "The client stopped acknowledging chunks")),
+            incoming @ Incoming::ConnectionLost | incoming @ Incoming::Disconnected => {
+                deferred.push_back(incoming);
+                return Err(Error::new(ErrorKind::NotConnected, "Lost the connection to the broker"));
+            },
+            incoming => deferred.push_back(incoming)
+        }
+    }
+    Ok(())
+}
+
+/// abort_stream()
+///
+/// Ends a get_data stream that couldn't be finished with an error envelope and the terminator
+fn abort_stream<T: Transport>(transport: &mut T, data: &GetData, error: &str) {
+    let envelope = ErrorEnvelope {
+        error:      error.to_string(),
+        table:      data.table.clone(),
+        start_ts:   data.start_ts,
+        end_ts:     data.end_ts
+    };
+    let result = rmps::to_vec_named(&envelope)
+        .map_err(|error| Error::new(ErrorKind::InvalidData, error))
+        .and_then(|buf| publish(transport, "topic_getdata", buf))
+        .and_then(|_| publish(transport, "topic_getdata", Vec::new()));
+    if let Err(error) = result {
+        error!("Could not send the error envelope! {:?}", error);
+    }
+}
+
+/// publish()
+/// 
+/// Publishes to MQTT given client, topic, and data
+#[allow(unused_variables)]
+fn publish<T: Transport>(transport: &mut T, topic: &str, data: Vec<u8>) -> Result<(), Error> {
+    // A stream can't go on while the broker is gone, its messages would be lost
+    if !transport.is_connected() {
+        return Err(Error::new(ErrorKind::NotConnected, "Lost the connection to the broker"));
+    }
+
+    // Publish request
+    // Note, the same topic cannot be used as a reply as it gets caught by this subscriber as well
+    // In the future, a topic reply might need to be given as well
+    transport.publish("Client", data)?;
+    info!("published");
+
+    Ok(())
+}
+
+/// subscribe()
+/// 
+/// Subscribes to a list(Vec) of topics
+fn subscribe<T: Transport>(transport: &mut T, topics: &[String]) {
+    // Subscribe to topics
+    for topic in topics {
+        if let Err(error) = transport.subscribe(topic) {
+            error!("Could not subscribe to {:?}! {:?}", topic, error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod handler_tests {
+    use super::*;
+    use crate::fixtures::{TempDatabase, TlsBroker, CERTS};
+    use std::io::{Read, Write};
+    use crate::transport::{MockBroker, MockTransport};
+
+    /// run()
+    ///
+    /// Serves everything published to the broker, then returns what the handler published
+    fn run(database: &Database, broker: &MockBroker, transport: MockTransport, stream: &parser::StreamConfig) -> Vec<Message> {
+        let writer = Mutex::new(Writer::new(database.clone()).unwrap());
+        let running = AtomicBool::new(true);
+        broker.disconnect();
+        serve(transport, database, &writer, topics(), &status(), stream, &running);
+        return broker.published();
+    }
+
+    /// topics()
+    ///
+    /// The topics tests subscribe to
+    fn topics() -> Vec<String> {
+        vec!["topic_getdata".to_string(), "topic1".to_string(), ACK_TOPIC.to_string()]
+    }
+
+    /// status()
+    ///
+    /// A status without heartbeats during tests
+    fn status() -> Status {
+        Status {
+            topic:      "status".to_string(),
+            heartbeat:  time::Duration::from_secs(3600)
+        }
+    }
+
+    /// request()
+    ///
+    /// Encodes a GetData request
+    fn request(table: &str, start_ts: u32, end_ts: u32) -> Vec<u8> {
+        rmps::to_vec(&GetData{table: table.to_string(), start_ts, end_ts, ..Default::default()}).unwrap()
+    }
+
+    /// records()
+    ///
+    /// The records of a range
+    fn records(database: &Database, table: &str, start_ts: u32, end_ts: u32) -> Vec<MpdRecordType> {
+        let mut cursor = database.get_data(table, start_ts, end_ts).unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = cursor.try_next().unwrap() {
+            records.push(record);
+        }
+        return records;
+    }
+
+    /// chunks()
+    ///
+    /// The chunks expected for the records of a range, 50 records each
+    fn chunks(database: &Database, table: &str, start_ts: u32, end_ts: u32) -> Vec<Vec<u8>> {
+        let mut cursor = database.get_data(table, start_ts, end_ts).unwrap();
+        let mut chunks = Vec::new();
+        let mut records = Vec::new();
+        let mut next = None;
+        loop {
+            let record = cursor.try_next().unwrap();
+            if records.len() == 50 || (record.is_none() && !records.is_empty()) {
+                let chunk = Chunk {
+                    seq:        chunks.len() as u32,
+                    records:    std::mem::take(&mut records),
+                    next:       next.take().unwrap(),
+                    more:       record.is_some()
+                };
+                chunks.push(rmps::to_vec(&chunk).unwrap());
+            }
+            match record {
+                Some(record) => {
+                    records.push(record);
+                    next = cursor.continuation();
+                },
+                None => return chunks
+            }
+        }
+    }
+
+    #[test]
+    fn test_mqtt_options() {
+        let config = parser::Config {
+            ip:                 "10.0.0.2".to_string(),
+            port:               8883,
+            client_id:          "node-7".to_string(),
+            keep_alive_secs:    10,
+            clean_session:      false,
+            username:           Some("node".to_string()),
+            password:           Some("secret".to_string()),
+            ..Default::default()
+        };
+
+        let mqtt_options = mqtt_options(&config).unwrap();
+        assert_eq!(mqtt_options.broker_address(), ("10.0.0.2".to_string(), 8883));
+        assert_eq!(mqtt_options.client_id(), "node-7");
+        assert_eq!(mqtt_options.keep_alive(), time::Duration::from_secs(10));
+        assert!(!mqtt_options.clean_session());
+        match mqtt_options.security_opts() {
+            SecurityOptions::UsernamePassword(username, password) => assert_eq!((username.as_str(), password.as_str()), ("node", "secret")),
+            security => panic!("Unexpected security options {:?}", security)
+        }
+        let last_will = mqtt_options.last_will().unwrap();
+        assert_eq!((last_will.topic.as_str(), last_will.message.as_str(), last_will.retain), ("LocalStorage/status", OFFLINE, true));
+    }
+
+    /// tls_config()
+    ///
+    /// A config for the TLS broker stand-in
+    fn tls_config(port: u16, client_auth: bool) -> parser::Config {
+        let cert = |name: &str| Some(format!("{}/{}", CERTS, name));
+        parser::Config {
+            ip:                 "localhost".to_string(),
+            port:               u32::from(port),
+            client_id:          "tls-node".to_string(),
+            ca_file:            cert("ca.pem"),
+            client_cert_file:   if client_auth { cert("client.pem") } else { None },
+            client_key_file:    if client_auth { cert("client.key") } else { None },
+            ..Default::default()
+        }
+    }
+
+    /// tls_connect()
+    ///
+    /// Connects to the broker stand-in the way rumqtt does with the TLS
+    /// settings of 'mqtt_options', then sends a CONNECT and waits for the CONNACK
+    fn tls_connect(mqtt_options: &MqttOptions) -> Result<(), Error> {
+        let mut config = rustls::ClientConfig::new();
+        config.root_store.add_pem_file(&mut &mqtt_options.ca().unwrap()[..]).unwrap();
+        if let Some((cert, key)) = mqtt_options.client_auth() {
+            let certs = pemfile::certs(&mut &cert[..]).unwrap();
+            let key = pemfile::rsa_private_keys(&mut &key[..]).unwrap().remove(0);
+            config.set_single_client_cert(certs, key);
+        }
+        let (host, port) = mqtt_options.broker_address();
+        let mut session = rustls::ClientSession::new(&Arc::new(config), webpki::DNSNameRef::try_from_ascii_str(&host).unwrap());
+        let mut tcp = std::net::TcpStream::connect(("127.0.0.1", port))?;
+        let mut stream = rustls::Stream::new(&mut session, &mut tcp);
+
+        // CONNECT with protocol "MQTT" level 4, a clean session and the client id
+        let client_id = mqtt_options.client_id();
+        let mut packet = vec![0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 0x3c, 0x00, client_id.len() as u8];
+        packet.extend(client_id.as_bytes());
+        stream.write_all(&[0x10, packet.len() as u8])?;
+        stream.write_all(&packet)?;
+        let mut connack = [0; 4];
+        stream.read_exact(&mut connack)?;
+        assert_eq!(connack, [0x20, 0x02, 0x00, 0x00]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mqtt_tls() {
+        // A client certificate signed by the broker's CA is accepted
+        let broker = TlsBroker::start(true);
+        let config = tls_config(broker.port, true);
+        config.validate().unwrap();
+        tls_connect(&mqtt_options(&config).unwrap()).unwrap();
+        assert_eq!(broker.clients.recv_timeout(time::Duration::from_secs(10)).unwrap().unwrap(), "tls-node");
+
+        // Without one the broker refuses the connection
+        let broker = TlsBroker::start(true);
+        assert!(tls_connect(&mqtt_options(&tls_config(broker.port, false)).unwrap()).is_err());
+        assert!(broker.clients.recv_timeout(time::Duration::from_secs(10)).unwrap().is_err());
+
+        // A broker that isn't signed by the CA is refused
+        let broker = TlsBroker::start(false);
+        let mut config = tls_config(broker.port, false);
+        config.ca_file = Some(format!("{}/client.pem", CERTS));
+        assert!(tls_connect(&mqtt_options(&config).unwrap()).is_err());
+
+        // Unusable files are found before connecting
+        let mut config = tls_config(broker.port, true);
+        config.client_key_file = config.client_cert_file.clone();
+        assert_eq!(mqtt_options(&config).unwrap_err().kind(), ErrorKind::InvalidData);
+        config.ca_file = Some(format!("{}/missing.pem", CERTS));
+        assert_eq!(mqtt_options(&config).unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_mqtt_tls_transport() {
+        // The transport the handler uses, with and without a client certificate
+        for client_auth in [false, true].iter() {
+            let broker = TlsBroker::start(*client_auth);
+            let transport = MqttTransport::connect(mqtt_options(&tls_config(broker.port, *client_auth)).unwrap(), Backoff::new(time::Duration::from_secs(1), time::Duration::from_secs(1))).unwrap();
+            assert_eq!(broker.clients.recv_timeout(time::Duration::from_secs(10)).unwrap().unwrap(), "tls-node");
+            drop(transport);
+        }
+    }
+
+    #[test]
+    fn test_handler_get_data() {
+        let database = TempDatabase::new();
+        // 2020-01-01 22:00 to 23:59, 60 records per hour
+        let mut shards = database.shards("levels");
+        for i in 0..120 {
+            shards = shards.at(1577916000 + i * 60);
+        }
+
+        let (transport, broker) = MockTransport::new();
+        assert!(broker.publish("topic_getdata", request("levels", 1577916000, 1577919540)));  // 22:00 to 22:59
+        assert!(broker.publish("topic_getdata", request("levels", 1577916000, 1577923199)));  // 22:00 to 23:59
+        assert!(broker.publish("topic_getdata", request("levels", 1577923200, 1577926800)));  // Nothing there
+        let published = run(&database, &broker, transport, &parser::StreamConfig::default());
+
+        // 50 + 10 records, then 50 + 50 + 20, then none, each followed by an empty terminator
+        let mut expected = chunks(&database, "levels", 1577916000, 1577919540);
+        expected.push(Vec::new());
+        expected.extend(chunks(&database, "levels", 1577916000, 1577923199));
+        expected.push(Vec::new());
+        expected.push(Vec::new());
+        assert_eq!(expected.len(), 8);
+
+        assert!(published.iter().all(|message| message.topic == "Client"));
+        assert_eq!(published.into_iter().map(|message| message.payload).collect::<Vec<Vec<u8>>>(), expected);
+    }
+
+    #[test]
+    fn test_handler_reconnect() {
+        let database = TempDatabase::new();
+        let mut shards = database.shards("levels");
+        for i in 0..120 {
+            shards = shards.at(1577916000 + i * 60);
+        }
+
+        // The broker restarts after the first chunk of the first request
+        let (transport, broker) = MockTransport::new();
+        broker.drop_after(1);
+        assert!(broker.publish("topic_getdata", request("levels", 1577916000, 1577923199)));  // 22:00 to 23:59
+        assert!(broker.publish("topic_getdata", request("levels", 1577916000, 1577916599)));  // 22:00 to 22:09
+        let published = run(&database, &broker, transport, &parser::StreamConfig::default());
+        let payloads = published.into_iter().map(|message| message.payload).collect::<Vec<Vec<u8>>>();
+
+        // The first chunk, the error envelope and a terminator, then the second request in full
+        let first = chunks(&database, "levels", 1577916000, 1577923199);
+        let envelope: ErrorEnvelope = rmps::from_slice(&payloads[1]).unwrap();
+        assert_eq!(payloads[0], first[0]);
+        assert_eq!((envelope.table.as_str(), envelope.start_ts, envelope.end_ts), ("levels", 1577916000, 1577923199));
+        assert!(rmps::from_slice::<ErrorEnvelope>(&payloads[0]).is_err());
+        assert_eq!(payloads[2], Vec::<u8>::new());
+
+        let mut expected = chunks(&database, "levels", 1577916000, 1577916599);
+        expected.push(Vec::new());
+        assert_eq!(payloads[3..].to_vec(), expected);
+
+        // Subscriptions lost with the clean session are made again
+        assert_eq!(broker.subscriptions(), topics());
+    }
+
+    #[test]
+    fn test_handler_chunk_bytes() {
+        let database = TempDatabase::new();
+        database.shards("levels").records("20200101", "22", 120);
+        let stream = parser::StreamConfig {
+            chunk_bytes: 2000,
+            ..Default::default()
+        };
+
+        let (transport, broker) = MockTransport::new();
+        assert!(broker.publish("topic_getdata", request("levels", 1577916000, 1577919540)));
+        let mut published = run(&database, &broker, transport, &stream);
+        assert_eq!(published.pop().unwrap().payload, Vec::<u8>::new());
+
+        // Numbered chunks that stay within the limit hold every record in order
+        let mut received = Vec::new();
+        for (seq, message) in published.iter().enumerate() {
+            let chunk: Chunk = rmps::from_slice(&message.payload).unwrap();
+            let bytes: usize = chunk.records.iter().map(|record| database::serialize_struct(record).unwrap().len()).sum();
+            assert_eq!(chunk.seq, seq as u32);
+            assert!(bytes <= stream.chunk_bytes || chunk.records.len() == 1);
+            assert!(chunk.records.len() <= stream.chunk_records);
+            received.extend(chunk.records);
+        }
+        assert!(published.len() > 3);
+        assert_eq!(received, records(&database, "levels", 1577916000, 1577919540));
+    }
+
+    #[test]
+    fn test_handler_ack_window() {
+        let database = TempDatabase::new();
+        database.shards("levels").records("20200101", "22", 120).records("20200101", "23", 10);
+        let windowed = GetData{table: "levels".to_string(), start_ts: 1577916000, end_ts: 1577919540, window: 1, ..Default::default()};
+
+        // The second request and the acknowledgements arrive while the first one waits
+        let (transport, broker) = MockTransport::new();
+        assert!(broker.publish("topic_getdata", rmps::to_vec(&windowed).unwrap()));
+        assert!(broker.publish("topic_getdata", request("levels", 1577919600, 1577923199)));
+        for seq in 0..3 {
+            assert!(broker.publish(ACK_TOPIC, rmps::to_vec(&Ack{seq}).unwrap()));
+        }
+        let published = run(&database, &broker, transport, &parser::StreamConfig::default());
+
+        let mut expected = chunks(&database, "levels", 1577916000, 1577919540);
+        expected.push(Vec::new());
+        expected.extend(chunks(&database, "levels", 1577919600, 1577923199));
+        expected.push(Vec::new());
+        assert_eq!(published.into_iter().map(|message| message.payload).collect::<Vec<Vec<u8>>>(), expected);
+
+        // Requests without a window still decode
+        let request: GetData = rmps::from_slice(&rmps::to_vec(&("levels", 1577916000, 1577919540)).unwrap()).unwrap();
+        assert_eq!(request.window, 0);
+    }
+
+    #[test]
+    fn test_handler_resume() {
+        let database = TempDatabase::new();
+        database.shards("levels").records("20200101", "22", 120).records("20200101", "23", 30);
+        let first: Chunk = rmps::from_slice(&chunks(&database, "levels", 1577916000, 1577923199)[0]).unwrap();
+
+        // Resuming after the first chunk returns the rest of the range
+        let resume = GetData{table: "levels".to_string(), start_ts: 1577916000, end_ts: 1577923199, after: Some(first.next), ..Default::default()};
+        let (transport, broker) = MockTransport::new();
+        assert!(broker.publish("topic_getdata", rmps::to_vec(&resume).unwrap()));
+        let mut wrong_table = resume.clone();
+        wrong_table.table = "other".to_string();
+        assert!(broker.publish("topic_getdata", rmps::to_vec(&wrong_table).unwrap()));  // Refused with an error envelope
+        let mut published = run(&database, &broker, transport, &parser::StreamConfig::default());
+        assert_eq!(published.pop().unwrap().payload, Vec::<u8>::new());
+        let envelope: ErrorEnvelope = rmps::from_slice(&published.pop().unwrap().payload).unwrap();
+        assert_eq!(envelope.table, "other");
+        assert_eq!(published.pop().unwrap().payload, Vec::<u8>::new());
+
+        let mut received = Vec::new();
+        for message in published {
+            let chunk: Chunk = rmps::from_slice(&message.payload).unwrap();
+            received.extend(chunk.records);
+        }
+        assert_eq!(received, records(&database, "levels", 1577916000, 1577923199).split_off(50));
+    }
+
+    #[test]
+    fn test_handler_limit() {
+        let database = TempDatabase::new();
+        database.shards("levels").records("20200101", "22", 120);
+        let all = records(&database, "levels", 1577916000, 1577919540);
+        let page = |limit, offset, after| GetData{table: "levels".to_string(), start_ts: 1577916000, end_ts: 1577919540, limit, offset, after, ..Default::default()};
+
+        // Replies of (records, whether more remain) for a request
+        let reply = |request: &GetData| {
+            let (transport, broker) = MockTransport::new();
+            assert!(broker.publish("topic_getdata", rmps::to_vec(request).unwrap()));
+            let mut published = run(&database, &broker, transport, &parser::StreamConfig::default());
+            assert_eq!(published.pop().unwrap().payload, Vec::<u8>::new());
+            let chunks: Vec<Chunk> = published.iter().map(|message| rmps::from_slice(&message.payload).unwrap()).collect();
+            assert!(chunks.iter().rev().skip(1).all(|chunk| chunk.more));
+            let more = chunks.last().is_some_and(|chunk| chunk.more);
+            let next = chunks.last().map(|chunk| chunk.next.clone());
+            (chunks.into_iter().flat_map(|chunk| chunk.records).collect::<Vec<MpdRecordType>>(), more, next)
+        };
+
+        // The first 60 records, then the next page after them
+        let (first, more, next) = reply(&page(60, 0, None));
+        assert_eq!((first.len(), more), (60, true));
+        assert_eq!(first[..], all[..60]);
+        let (second, more, _) = reply(&page(60, 0, next));
+        assert_eq!((second.len(), more), (60, false));
+        assert_eq!(second[..], all[60..]);
+
+        // Exactly what is there, an offset, and an offset past the end
+        assert!(!reply(&page(120, 0, None)).1);
+        let (last, more, _) = reply(&page(0, 100, None));
+        assert_eq!(last[..], all[100..]);
+        assert!(!more);
+        assert!(reply(&page(10, 200, None)).0.is_empty());
+    }
+
+    #[test]
+    fn test_handler_fields() {
+        let database = TempDatabase::new();
+        database.shards("levels").records("20200101", "22", 60);
+        database.insert_record("levels", MpdRecordType::new(1577916030, vec![0xc1])).unwrap();  // Can't be decoded
+        let request = GetData{table: "levels".to_string(), start_ts: 1577916000, end_ts: 1577919540, fields: vec!["PM2_5".to_string(), "T".to_string()], ..Default::default()};
+
+        let (transport, broker) = MockTransport::new();
+        assert!(broker.publish("topic_getdata", rmps::to_vec(&request).unwrap()));
+        let mut published = run(&database, &broker, transport, &parser::StreamConfig::default());
+        assert_eq!(published.pop().unwrap().payload, Vec::<u8>::new());
+
+        // Every decodable record, with only the two fields
+        let received: Vec<MpdRecordType> = published.iter()
+            .flat_map(|message| rmps::from_slice::<Chunk>(&message.payload).unwrap().records)
+            .collect();
+        let all: Vec<MpdRecordType> = records(&database, "levels", 1577916000, 1577919540).into_iter().filter(|record| record.datalog != [0xc1]).collect();
+        assert_eq!(received.len(), 60);
+        for (record, original) in received.iter().zip(all) {
+            let fields = query::fields(&original.datalog).unwrap();
+            let expected: Vec<(String, query::Value)> = fields.into_iter().filter(|(name, _)| name == "PM2_5" || name == "T").collect();
+            assert_eq!(record.id, original.id);
+            assert_eq!(query::fields(&record.datalog).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_handler_filter() {
+        let database = TempDatabase::new();
+        database.shards("levels").records("20200101", "22", 120);
+        let filter = query::Filter::parse("NO2 > 5.0 or AQHI is null").unwrap();
+        let request = |filter: &str| GetData{table: "levels".to_string(), start_ts: 1577916000, end_ts: 1577919540, filter: Some(filter.to_string()), ..Default::default()};
+
+        let (transport, broker) = MockTransport::new();
+        assert!(broker.publish("topic_getdata", rmps::to_vec(&request("NO2 > 5.0 or AQHI is null")).unwrap()));
+        assert!(broker.publish("topic_getdata", rmps::to_vec(&request("NO2 >")).unwrap()));  // Not a filter, refused with an error envelope
+        let mut published = run(&database, &broker, transport, &parser::StreamConfig::default());
+        assert_eq!(published.pop().unwrap().payload, Vec::<u8>::new());
+        let envelope: ErrorEnvelope = rmps::from_slice(&published.pop().unwrap().payload).unwrap();
+        assert_eq!((envelope.table.as_str(), envelope.start_ts, envelope.end_ts), ("levels", 1577916000, 1577919540));
+        assert_eq!(published.pop().unwrap().payload, Vec::<u8>::new());
+
+        let received: Vec<MpdRecordType> = published.iter()
+            .flat_map(|message| rmps::from_slice::<Chunk>(&message.payload).unwrap().records)
+            .collect();
+        let expected: Vec<MpdRecordType> = records(&database, "levels", 1577916000, 1577919540).into_iter()
+            .filter(|record| filter.matches_datalog(&record.datalog))
+            .collect();
+        assert!(!expected.is_empty() && expected.len() < 120);
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_handler_aggregate() {
+        let database = TempDatabase::new();
+        database.shards("levels").records("20200101", "22", 120).records("20200101", "23", 30);
+        let request = GetData{table: "levels".to_string(), start_ts: 1577916000, end_ts: 1577923199, bucket_secs: 60, fields: vec!["NO2".to_string()], ..Default::default()};
+
+        // A bucket per minute: records are a second apart, so two minutes of the first hour and one of the next
+        let (transport, broker) = MockTransport::new();
+        assert!(broker.publish("topic_getdata", rmps::to_vec(&request).unwrap()));
+        let mut published = run(&database, &broker, transport, &parser::StreamConfig::default());
+        assert_eq!(published.pop().unwrap().payload, Vec::<u8>::new());
+
+        let received: Vec<query::Bucket> = published.iter()
+            .flat_map(|message| rmps::from_slice::<Chunk>(&message.payload).unwrap().records)
+            .map(|record| query::Bucket::from_record(&record).unwrap())
+            .collect();
+        let expected: Vec<query::Bucket> = database.aggregate("levels", 1577916000, 1577923199, 60, vec!["NO2".to_string()]).unwrap().collect();
+        assert_eq!(received.len(), 3);
+        assert_eq!(received, expected);
+        assert_eq!(received.iter().map(|bucket| bucket.records).sum::<u64>(), 150);
+    }
+
+    #[test]
+    fn test_handler_ack_timeout() {
+        let database = TempDatabase::new();
+        database.shards("levels").records("20200101", "22", 120);
+        let writer = Mutex::new(Writer::new(database.clone()).unwrap());
+        let stream = parser::StreamConfig {
+            ack_timeout_ms: 50,
+            ..Default::default()
+        };
+        let windowed = GetData{table: "levels".to_string(), start_ts: 1577916000, end_ts: 1577919540, window: 1, ..Default::default()};
+
+        // The client never acknowledges, so the stream ends after the first chunk
+        let (transport, broker) = MockTransport::new();
+        assert!(broker.publish("topic_getdata", rmps::to_vec(&windowed).unwrap()));
+        thread::scope(|scope| {
+            scope.spawn(|| serve(transport, &database, &writer, topics(), &status(), &stream, &AtomicBool::new(true)));
+            let started = time::Instant::now();
+            while broker.published().len() < 3 && started.elapsed() < time::Duration::from_secs(5) {
+                thread::sleep(time::Duration::from_millis(10));
+            }
+            broker.disconnect();
+        });
+
+        let payloads = broker.published().into_iter().map(|message| message.payload).collect::<Vec<Vec<u8>>>();
+        assert_eq!(payloads.len(), 3);
+        assert_eq!(payloads[0], chunks(&database, "levels", 1577916000, 1577919540)[0]);
+        let envelope: ErrorEnvelope = rmps::from_slice(&payloads[1]).unwrap();
+        assert_eq!((envelope.table.as_str(), envelope.start_ts), ("levels", 1577916000));
+        assert_eq!(payloads[2], Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_handler_backfill() {
+        let database = TempDatabase::new();
+        // The record of 2020-01-02 00:00 closes hour 23, its own hour is still open
+        database.shards("levels").records("20200101", "22", 10).records("20200101", "23", 5).at(1577923200);
+        let mut writer = Writer::new(database.clone()).unwrap();
+        writer.add_rollup(crate::rollup::Rollup::new("levels_1h", "levels", 3600, Vec::new()).unwrap());
+        let writer = Mutex::new(writer);
+
+        // Unknown tables and invalid requests are only logged
+        let (transport, broker) = MockTransport::new();
+        assert!(broker.publish("topic_backfill", rmps::to_vec(&Backfill{table: "levels_1d".to_string(), start_ts: 0, end_ts: u32::MAX}).unwrap()));
+        assert!(broker.publish("topic_backfill", rmps::to_vec(&"levels_1h").unwrap()));
+        assert!(broker.publish("topic_backfill", rmps::to_vec(&Backfill{table: "levels_1h".to_string(), start_ts: 0, end_ts: u32::MAX}).unwrap()));
+        broker.disconnect();
+        serve(transport, &database, &writer, vec!["topic_backfill".to_string()], &status(), &parser::StreamConfig::default(), &AtomicBool::new(true));
+        assert!(broker.published().is_empty());
+
+        let buckets: Vec<query::Bucket> = records(&database, "levels_1h", 0, u32::MAX).iter()
+            .map(|record| query::Bucket::from_record(record).unwrap())
+            .collect();
+        assert_eq!(buckets.iter().map(|bucket| (bucket.start, bucket.records)).collect::<Vec<(u32, u64)>>(), vec![(1577916000, 10), (1577919600, 5)]);
+    }
+
+    #[test]
+    fn test_handler_status() {
+        let database = TempDatabase::new();
+        database.shards("levels").records("20200101", "22", 10);
+        let database_bytes = database.storage().size().unwrap();
+        let writer = Mutex::new(Writer::new(database.clone()).unwrap());
+        let status = Status {
+            topic:      "status".to_string(),
+            heartbeat:  time::Duration::from_secs(0)
+        };
+
+        // A heartbeat follows the request adding 3 records
+        let (transport, broker) = MockTransport::new();
+        assert!(broker.publish("topic_add", rmps::to_vec(&"3").unwrap()));
+        broker.disconnect();
+        serve(transport, &database, &writer, vec!["topic_add".to_string()], &status, &parser::StreamConfig::default(), &AtomicBool::new(true));
+
+        let published = broker.published();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].topic, "status/heartbeat");
+        let heartbeat: Heartbeat = rmps::from_slice(&published[0].payload).unwrap();
+        assert_eq!(heartbeat.database_bytes, database_bytes);  // The added records are still buffered
+        assert!(heartbeat.last_ingest.is_some());
+
+        // Offline once stopped
+        assert_eq!(broker.retained("status"), Some(OFFLINE.as_bytes().to_vec()));
+    }
+
+    #[test]
+    fn test_handler_corrupt_file() {
+        let database = TempDatabase::new();
+        database.shards("levels").records("20200101", "22", 10);
+        database.storage().append("levels", &database::get_datetime(1577916000), &[0xc1]).unwrap();
+
+        // The valid records are sent, then the corruption ends the stream
+        let (transport, broker) = MockTransport::new();
+        assert!(broker.publish("topic_getdata", request("levels", 1577916000, 1577919540)));
+        let published = run(&database, &broker, transport, &parser::StreamConfig{chunk_records: 5, ..Default::default()});
+
+        let payloads = published.into_iter().map(|message| message.payload).collect::<Vec<Vec<u8>>>();
+        assert_eq!(payloads.len(), 3);
+        let chunk: Chunk = rmps::from_slice(&payloads[0]).unwrap();
+        assert_eq!((chunk.seq, chunk.records.len(), chunk.more), (0, 5, true));
+        let envelope: ErrorEnvelope = rmps::from_slice(&payloads[1]).unwrap();
+        assert_eq!((envelope.table.as_str(), envelope.start_ts, envelope.end_ts), ("levels", 1577916000, 1577919540));
+        assert!(envelope.error.contains("reserved marker"), "{}", envelope.error);
+        assert_eq!(payloads[2], Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_handler_bad_requests() {
+        let database = TempDatabase::new();
+        database.shards("levels").at(1577916000);
+
+        let (transport, broker) = MockTransport::new();
+        assert!(broker.publish("topic_getdata", vec![0xc1]));  // Not a GetData, answered with an empty envelope
+        assert!(broker.publish("topic_getdata", request("../levels", 1577916000, 1577919540)));  // Not a table
+        assert!(broker.publish("topic_unknown", request("levels", 1577916000, 1577919540)));  // Not subscribed
+        assert!(broker.publish("topic1", Vec::new()));
+        let published = run(&database, &broker, transport, &parser::StreamConfig::default());
+
+        assert_eq!(broker.subscriptions(), topics());
+        assert_eq!(published.len(), 4);
+        for (message, table, start_ts, end_ts) in [(&published[0], "", 0, 0), (&published[2], "../levels", 1577916000, 1577919540)].iter() {
+            let envelope: ErrorEnvelope = rmps::from_slice(&message.payload).unwrap();
+            assert_eq!((envelope.table.as_str(), envelope.start_ts, envelope.end_ts), (*table, *start_ts, *end_ts));
+        }
+        assert_eq!((&published[1].payload, &published[3].payload), (&Vec::new(), &Vec::new()));
+        assert!(!broker.publish("topic_getdata", request("levels", 1577916000, 1577919540)));  // Disconnected
+    }
+}
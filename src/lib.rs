@@ -0,0 +1,27 @@
+#![allow(clippy::needless_return)]
+//! LocalStorage
+//!
+//! A file system based time-series store for AirSENCE. The service binary
+//! is a thin layer over this library, so other programs can open the same
+//! data directory or run the MQTT handler themselves.
+extern crate rmp_serde as rmps;
+
+pub mod database;
+#[cfg(test)]
+mod fixtures;
+pub mod handler;
+pub mod parser;
+pub mod query;
+pub mod rollup;
+pub mod storage;
+pub mod table;
+pub mod transport;
+pub mod writer;
+
+pub use database::{Continuation, CorruptFile, Database, Entry, MpdRecordType, MyCursor};
+pub use query::Filter;
+pub use rollup::Rollup;
+pub use storage::{FsStorage, MemoryStorage, Storage};
+pub use table::{Table, TypedCursor};
+pub use transport::{MockBroker, MockTransport, MqttTransport, Transport};
+pub use writer::Writer;
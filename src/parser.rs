@@ -1,15 +1,88 @@
 use std::fs::File;
+use std::io;
 use std::io::Read;
+use std::net::IpAddr;
 use serde::{Serialize, Deserialize};
 use log::{error, warn};
 
+use crate::database;
+use crate::rollup::Rollup;
+
+/// Longest client id every MQTT 3.1.1 broker has to accept
+const MAX_CLIENT_ID_LENGTH: usize = 23;
+
+/// Shortest keep-alive rumqtt accepts
+const MIN_KEEP_ALIVE_SECS: u16 = 5;
+
 /// Config is the config for initialize the server
 /// Contain sensor initialize information
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
 	pub ip:     String,
     pub port:   u32,
-    pub topics: Vec<String>
+    pub topics: Vec<String>,
+    #[serde(default = "default_client_id")]
+    pub client_id:          String,         // Has to be unique per broker
+    #[serde(default = "default_keep_alive_secs")]
+    pub keep_alive_secs:    u16,
+    #[serde(default = "default_clean_session")]
+    pub clean_session:      bool,           // false keeps subscriptions and queued messages between connections
+    #[serde(default)]
+    pub username:           Option<String>,
+    #[serde(default)]
+    pub password:           Option<String>,
+    #[serde(default = "default_reconnect_secs")]
+    pub reconnect_secs:     u64,            // Wait before reconnecting to the broker
+    #[serde(default = "default_reconnect_max_secs")]
+    pub reconnect_max_secs: u64,            // Longest wait between connection attempts
+    #[serde(default = "default_status_topic")]
+    pub status_topic:       String,         // Retained online/offline status, heartbeats go to <status_topic>/heartbeat
+    #[serde(default = "default_heartbeat_secs")]
+    pub heartbeat_secs:     u64,
+    #[serde(default)]
+    pub ca_file:            Option<String>, // Connects over TLS when set, PEM certificates
+    #[serde(default)]
+    pub client_cert_file:   Option<String>, // PEM certificate chain for client authentication
+    #[serde(default)]
+    pub client_key_file:    Option<String>, // PEM RSA (PKCS#1) key of the client certificate
+    #[serde(default)]
+    pub writer: WriterConfig,
+    #[serde(default)]
+    pub stream: StreamConfig,
+    #[serde(default)]
+    pub rollups: Vec<RollupConfig>
+}
+
+/// WriterConfig is the optional [writer] section
+/// Controls how often buffered records are written to disk
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct WriterConfig {
+    pub max_buffered:       usize,  // Bytes buffered per table before flushing
+    pub flush_interval_ms:  u64,    // Longest time a record stays buffered
+    pub lateness_secs:      u64,    // How long an hour file accepts late records after the hour passed
+    pub dedup_tables:       Vec<String> // Tables that drop records already stored
+}
+
+/// RollupConfig is an optional [[rollups]] entry
+/// Names a table kept up to date with the buckets of another, see `rollup::Rollup`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RollupConfig {
+    pub table:          String,
+    pub source:         String,
+    pub bucket_secs:    u32,
+    #[serde(default)]
+    pub fields:         Vec<String>     // Every numeric field if empty
+}
+
+impl RollupConfig {
+    /// rollup()
+    ///
+    /// Checks the entry and returns its rollup
+    pub fn rollup(&self) -> Result<Rollup, io::Error> {
+        Rollup::new(&self.table, &self.source, self.bucket_secs, self.fields.clone())
+            .map_err(|error| invalid_config(format!("rollup {:?}: {}", self.table, error)))
+    }
 }
 
 /// Create a default empty struct
@@ -18,29 +91,186 @@ impl Default for Config {
         Config {
             ip:     "127.0.0.1".to_string(),
             port:   1883,
-            topics: vec!["topic1".to_string()]
+            topics: vec!["topic1".to_string()],
+            client_id:          default_client_id(),
+            keep_alive_secs:    default_keep_alive_secs(),
+            clean_session:      default_clean_session(),
+            username:           None,
+            password:           None,
+            reconnect_secs:     default_reconnect_secs(),
+            reconnect_max_secs: default_reconnect_max_secs(),
+            status_topic:       default_status_topic(),
+            heartbeat_secs:     default_heartbeat_secs(),
+            ca_file:            None,
+            client_cert_file:   None,
+            client_key_file:    None,
+            writer: WriterConfig::default(),
+            stream: StreamConfig::default(),
+            rollups: Vec::new()
         }
 	}
 }
 
+impl Config {
+    /// validate()
+    ///
+    /// Checks the MQTT settings before connecting
+    pub fn validate(&self) -> Result<(), io::Error> {
+        if self.ip.trim().is_empty() || self.ip.contains(char::is_whitespace) {
+            return Err(invalid_config(format!("ip {:?} is not a host name or address", self.ip)));
+        }
+        if self.port == 0 || self.port > u32::from(u16::MAX) {
+            return Err(invalid_config(format!("port {} is not between 1 and {}", self.port, u16::MAX)));
+        }
+        if self.client_id.is_empty() || self.client_id.contains(|c: char| c.is_whitespace() || c.is_control()) {
+            return Err(invalid_config(format!("client_id {:?} has to be a word without spaces", self.client_id)));
+        }
+        if self.client_id.len() > MAX_CLIENT_ID_LENGTH {
+            warn!("client_id {:?} is longer than {} characters, some brokers will refuse it", self.client_id, MAX_CLIENT_ID_LENGTH);
+        }
+        if self.keep_alive_secs < MIN_KEEP_ALIVE_SECS {
+            return Err(invalid_config(format!("keep_alive_secs has to be at least {}", MIN_KEEP_ALIVE_SECS)));
+        }
+        if self.password.is_some() && self.username.is_none() {
+            return Err(invalid_config("password is set without a username".to_string()));
+        }
+        if self.reconnect_secs == 0 || self.reconnect_max_secs < self.reconnect_secs {
+            return Err(invalid_config("reconnect_secs has to be at least 1 and at most reconnect_max_secs".to_string()));
+        }
+        if self.status_topic.is_empty() || self.status_topic.contains(['+', '#']) {
+            return Err(invalid_config(format!("status_topic {:?} has to be a topic without wildcards", self.status_topic)));
+        }
+        if self.heartbeat_secs == 0 {
+            return Err(invalid_config("heartbeat_secs has to be at least 1".to_string()));
+        }
+        if self.writer.max_buffered == 0 || self.writer.flush_interval_ms == 0 {
+            return Err(invalid_config("max_buffered and flush_interval_ms of [writer] have to be at least 1".to_string()));
+        }
+        for table in &self.writer.dedup_tables {
+            database::validate_table_name(table).map_err(|error| invalid_config(format!("dedup table {:?}: {}", table, error)))?;
+        }
+        if self.stream.chunk_records == 0 || self.stream.ack_timeout_ms == 0 {
+            return Err(invalid_config("chunk_records and ack_timeout_ms of [stream] have to be at least 1".to_string()));
+        }
+        for (index, rollup) in self.rollups.iter().enumerate() {
+            rollup.rollup()?;
+            if self.rollups[..index].iter().any(|other| other.table == rollup.table) {
+                return Err(invalid_config(format!("rollup {:?} is defined twice", rollup.table)));
+            }
+            if self.rollups.iter().any(|other| other.table == rollup.source) {
+                return Err(invalid_config(format!("rollup {:?} can't roll up the rollup {:?}", rollup.table, rollup.source)));
+            }
+        }
+        if self.client_cert_file.is_some() != self.client_key_file.is_some() {
+            return Err(invalid_config("client_cert_file and client_key_file have to be set together".to_string()));
+        }
+        if self.ca_file.is_some() && self.ip.parse::<IpAddr>().is_ok() {
+            return Err(invalid_config(format!("ip {:?} has to be a host name to connect over TLS, the broker's certificate is checked against it", self.ip)));
+        }
+        if self.client_cert_file.is_some() && self.ca_file.is_none() {
+            return Err(invalid_config("client certificates need a ca_file to connect over TLS".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Defaults of the optional MQTT settings
+fn default_client_id() -> String {
+    "LocalDB".to_string()
+}
+
+fn default_keep_alive_secs() -> u16 {
+    60
+}
+
+fn default_clean_session() -> bool {
+    true
+}
+
+fn default_reconnect_secs() -> u64 {
+    5
+}
+
+fn default_reconnect_max_secs() -> u64 {
+    60
+}
+
+fn default_status_topic() -> String {
+    "LocalStorage/status".to_string()
+}
+
+fn default_heartbeat_secs() -> u64 {
+    30
+}
+
+/// invalid_config()
+///
+/// Creates the error for a setting that can't be used
+fn invalid_config(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid config: {}", reason))
+}
+
+/// StreamConfig is the optional [stream] section
+/// Controls how get_data replies are split into chunks
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StreamConfig {
+    pub chunk_records:  usize,  // Most records per chunk
+    pub chunk_bytes:    usize,  // Most bytes of records per chunk, 0 for no limit
+    pub ack_timeout_ms: u64,    // How long to wait for a client that asked for a window of acknowledgements
+}
+
+/// Create a default writer config
+impl Default for WriterConfig {
+    fn default () -> WriterConfig {
+        WriterConfig {
+            max_buffered:       16 * 1024,
+            flush_interval_ms:  5000,
+            lateness_secs:      0,
+            dedup_tables:       Vec::new()
+        }
+    }
+}
+
+/// Create a default stream config
+impl Default for StreamConfig {
+    fn default () -> StreamConfig {
+        StreamConfig {
+            chunk_records:  50,
+            chunk_bytes:    0,
+            ack_timeout_ms: 30000
+        }
+    }
+}
+
 
 /// parse()
 ///
 /// Read toml files and return a struct consisting of
 /// a map of sensors and modbus's
-pub fn parse (path: &String) -> Result<Config, ()> {
+pub fn parse (path: &String) -> Result<Config, io::Error> {
     // Get toml file
-    let toml_file = read_file(&path);
+    let toml_file = read_file(path);
+    return parse_str(&toml_file);
+}
 
-    // Attempt to Parse, if error return default empty Config
-    let config: Config = match toml::from_str(&toml_file) {
+/// parse_str()
+///
+/// Parse and validate the contents of a toml file
+pub fn parse_str(toml_file: &str) -> Result<Config, io::Error> {
+    // Attempt to Parse
+    let config: Config = match toml::from_str(toml_file) {
         Ok(config) => config,
         Err(err) => {
             error!{"Error! Couldn't read file. \n{:?}", err};
-            return Err(());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, err));
         }
     };
 
+    if let Err(err) = config.validate() {
+        error!("{}", err);
+        return Err(err);
+    }
     return Ok(config);
 }
 
@@ -52,7 +282,7 @@ pub fn read_file(path: &String) -> String {
     let mut toml_file = String::new();
 
     // Attempting to open file
-    let mut file = match File::open(&path) {
+    let mut file = match File::open(path) {
         Ok(file) => file,
         Err(_)  => {
             error!("Could not find config file, returning empty string.");
@@ -70,4 +300,73 @@ pub fn read_file(path: &String) -> String {
     }
 
     return toml_file;
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod parser_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mqtt_settings() {
+        // Settings left out get their defaults
+        let config = parse_str("ip = \"10.0.0.2\"\nport = 8883\ntopics = [\"topic1\"]").unwrap();
+        assert_eq!(config.client_id, "LocalDB");
+        assert_eq!(config.keep_alive_secs, 60);
+        assert!(config.clean_session);
+        assert_eq!(config.username, None);
+        assert_eq!(config.status_topic, "LocalStorage/status");
+        assert_eq!(config.heartbeat_secs, 30);
+        assert_eq!(config.stream.chunk_records, 50);
+
+        let config = parse_str("ip = \"broker\"\nport = 1883\ntopics = []\n[stream]\nchunk_bytes = 4096").unwrap();
+        assert_eq!((config.stream.chunk_records, config.stream.chunk_bytes), (50, 4096));
+
+        let config = parse_str("ip = \"broker\"\nport = 1883\ntopics = []\n[[rollups]]\ntable = \"levels_1h\"\nsource = \"levels\"\nbucket_secs = 3600").unwrap();
+        assert_eq!(config.rollups[0].rollup().unwrap(), Rollup::new("levels_1h", "levels", 3600, Vec::new()).unwrap());
+
+        let config = parse_str("ip = \"broker\"\nport = 1883\ntopics = []\nclient_id = \"node-7\"\nkeep_alive_secs = 10\nclean_session = false\nusername = \"node\"\npassword = \"secret\"").unwrap();
+        assert_eq!(config.client_id, "node-7");
+        assert_eq!(config.keep_alive_secs, 10);
+        assert!(!config.clean_session);
+        assert_eq!(config.password, Some("secret".to_string()));
+
+        // A partial [writer] section keeps the other defaults
+        let config = parse_str("ip = \"broker\"\nport = 1883\ntopics = []\n[writer]\nlateness_secs = 60").unwrap();
+        assert_eq!((config.writer.max_buffered, config.writer.flush_interval_ms, config.writer.lateness_secs), (16 * 1024, 5000, 60));
+
+        // The config in the repository is valid
+        assert!(parse(&"config/config.toml".to_string()).is_ok());
+    }
+
+    #[test]
+    fn test_validate_mqtt_settings() {
+        let invalid = [
+            "ip = \"\"\nport = 1883\ntopics = []",
+            "ip = \"broker\"\nport = 0\ntopics = []",
+            "ip = \"broker\"\nport = 70000\ntopics = []",
+            "ip = \"broker\"\nport = 1883\ntopics = []\nclient_id = \"\"",
+            "ip = \"broker\"\nport = 1883\ntopics = []\nclient_id = \" node\"",
+            "ip = \"broker\"\nport = 1883\ntopics = []\nkeep_alive_secs = 1",
+            "ip = \"broker\"\nport = 1883\ntopics = []\npassword = \"secret\"",
+            "ip = \"broker\"\nport = 1883\ntopics = []\n[writer]\nflush_interval_ms = 0",
+            "ip = \"broker\"\nport = 1883\ntopics = []\n[writer]\nmax_buffered = 0",
+            "ip = \"broker\"\nport = 1883\ntopics = []\n[writer]\ndedup_tables = [\"../levels\"]",
+            "ip = \"broker\"\nport = 8883\ntopics = []\nca_file = \"ca.pem\"\nclient_cert_file = \"client.pem\"",
+            "ip = \"10.0.0.2\"\nport = 8883\ntopics = []\nca_file = \"ca.pem\"",
+            "ip = \"broker\"\nport = 1883\ntopics = []\nreconnect_secs = 0",
+            "ip = \"broker\"\nport = 1883\ntopics = []\nstatus_topic = \"\"",
+            "ip = \"broker\"\nport = 1883\ntopics = []\nstatus_topic = \"LocalStorage/#\"",
+            "ip = \"broker\"\nport = 1883\ntopics = []\nheartbeat_secs = 0",
+            "ip = \"broker\"\nport = 1883\ntopics = []\n[stream]\nchunk_records = 0",
+            "ip = \"broker\"\nport = 1883\ntopics = []\nreconnect_secs = 10\nreconnect_max_secs = 5",
+            "ip = \"broker\"\nport = 1883\ntopics = []\n[[rollups]]\ntable = \"levels_7m\"\nsource = \"levels\"\nbucket_secs = 420",
+            "ip = \"broker\"\nport = 1883\ntopics = []\n[[rollups]]\ntable = \"levels_1h\"\nsource = \"levels\"\nbucket_secs = 3600\n[[rollups]]\ntable = \"levels_1h\"\nsource = \"levels\"\nbucket_secs = 60",
+            "ip = \"broker\"\nport = 1883\ntopics = []\n[[rollups]]\ntable = \"levels_1h\"\nsource = \"levels\"\nbucket_secs = 3600\n[[rollups]]\ntable = \"levels_1d\"\nsource = \"levels_1h\"\nbucket_secs = 86400",
+            "ip = \"broker\"\nport = 8883\ntopics = []\nclient_cert_file = \"client.pem\"\nclient_key_file = \"client.key\"",
+        ];
+        for toml_file in invalid.iter() {
+            assert_eq!(parse_str(toml_file).unwrap_err().kind(), io::ErrorKind::InvalidInput, "{}", toml_file);
+        }
+        assert!(Config::default().validate().is_ok());
+    }
+}
@@ -0,0 +1,737 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{self, MapAccess, SeqAccess, Visitor};
+use serde::ser::{SerializeMap, SerializeSeq};
+use log::error;
+
+use crate::database::{self, Continuation, MpdRecordType, MyCursor, RAW_DATA_FIELDS};
+
+/// Value
+///
+/// Any MsgPack value, for looking into a record's datalog without knowing its type
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    F32(f32),
+    F64(f64),
+    String(String),
+    Binary(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+/// ValueVisitor
+///
+/// Builds a `Value` from whatever the deserializer finds
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a MsgPack value")
+    }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<Value, E> {
+        Ok(Value::Bool(value))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Value, E> {
+        Ok(Value::Int(value))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Value, E> {
+        Ok(Value::UInt(value))
+    }
+
+    fn visit_f32<E: de::Error>(self, value: f32) -> Result<Value, E> {
+        Ok(Value::F32(value))
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Value, E> {
+        Ok(Value::F64(value))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Value, E> {
+        Ok(Value::String(value.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, value: String) -> Result<Value, E> {
+        Ok(Value::String(value))
+    }
+
+    fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Value, E> {
+        Ok(Value::Binary(value.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> Result<Value, E> {
+        Ok(Value::Binary(value))
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        Value::deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut values = Vec::new();
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(Value::Array(values))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut entries = Vec::new();
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(Value::Map(entries))
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Nil => serializer.serialize_unit(),
+            Value::Bool(value) => serializer.serialize_bool(*value),
+            Value::Int(value) => serializer.serialize_i64(*value),
+            Value::UInt(value) => serializer.serialize_u64(*value),
+            Value::F32(value) => serializer.serialize_f32(*value),
+            Value::F64(value) => serializer.serialize_f64(*value),
+            Value::String(value) => serializer.serialize_str(value),
+            Value::Binary(value) => serializer.serialize_bytes(value),
+            Value::Array(values) => {
+                let mut seq = serializer.serialize_seq(Some(values.len()))?;
+                for value in values {
+                    seq.serialize_element(value)?;
+                }
+                seq.end()
+            },
+            Value::Map(entries) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, value) in entries {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+/// fields()
+///
+/// Decodes a datalog into its named fields. A map is read by its string
+/// keys, an array as a `RawData` encoded without field names.
+pub fn fields(datalog: &[u8]) -> Result<Vec<(String, Value)>, io::Error> {
+    // Datalogs come from hour files, don't let deep nesting exhaust the stack
+    database::record_length(datalog).map_err(|reason| io::Error::new(io::ErrorKind::InvalidData, reason))?;
+    let value: Value = rmps::from_slice(datalog).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    match value {
+        Value::Map(entries) => Ok(entries.into_iter()
+            .filter_map(|(key, value)| match key {
+                Value::String(key) => Some((key, value)),
+                _ => None
+            })
+            .collect()),
+        Value::Array(values) => Ok(RAW_DATA_FIELDS.iter().map(|name| name.to_string()).zip(values).collect()),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "The datalog is neither a map nor an array"))
+    }
+}
+
+/// project()
+///
+/// Encodes only the requested fields of a datalog as a map, in the order
+/// they were requested. Fields the datalog doesn't have are nil.
+pub fn project(datalog: &[u8], names: &[String]) -> Result<Vec<u8>, io::Error> {
+    let mut fields = fields(datalog)?;
+    let projected = names.iter()
+        .map(|name| {
+            let value = match fields.iter().position(|(field, _)| field == name) {
+                Some(index) => fields.swap_remove(index).1,
+                None => Value::Nil
+            };
+            (Value::String(name.clone()), value)
+        })
+        .collect();
+    rmps::to_vec(&Value::Map(projected)).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+/// Parentheses nest at most this deep in a filter
+const MAX_FILTER_DEPTH: usize = 32;
+
+/// Filter
+///
+/// A condition on the fields of a datalog, parsed from an expression like
+/// `NO2 > 5.0 and (AQHI is null or T <= -10)`. A field that is missing or
+/// nil, or of another type than the value it is compared to, fails every
+/// comparison; only `is null` matches it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Compare(String, Comparison, Literal),
+    IsNull(String),
+    NotNull(String),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+/// Comparison
+///
+/// How a field is compared to a literal: = != < <= > >=
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Literal
+///
+/// A value in a filter: a number, a 'string' or "string", true or false
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Number(f64),
+    String(String),
+    Bool(bool),
+}
+
+/// Token
+///
+/// A word of a filter expression
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Literal(Literal),
+    Comparison(Comparison),
+    Open,
+    Close,
+}
+
+impl Filter {
+    /// parse()
+    ///
+    /// Parses a filter expression. 'and' binds tighter than 'or'.
+    pub fn parse(expression: &str) -> Result<Filter, io::Error> {
+        let tokens = tokenize(expression)?;
+        let mut position = 0;
+        let filter = parse_or(&tokens, &mut position, 0)?;
+        if position < tokens.len() {
+            return Err(invalid_filter(format!("unexpected {:?}", tokens[position])));
+        }
+        return Ok(filter);
+    }
+
+    /// matches()
+    ///
+    /// Checks the filter against the named fields of a datalog
+    pub fn matches(&self, fields: &[(String, Value)]) -> bool {
+        let field = |name: &str| fields.iter().find(|(field, _)| field == name).map(|(_, value)| value);
+        match self {
+            Filter::Compare(name, comparison, literal) => match field(name).and_then(|value| compare(value, literal)) {
+                Some(ordering) => match comparison {
+                    Comparison::Eq => ordering == Ordering::Equal,
+                    Comparison::Ne => ordering != Ordering::Equal,
+                    Comparison::Lt => ordering == Ordering::Less,
+                    Comparison::Le => ordering != Ordering::Greater,
+                    Comparison::Gt => ordering == Ordering::Greater,
+                    Comparison::Ge => ordering != Ordering::Less
+                },
+                None => false
+            },
+            Filter::IsNull(name) => matches!(field(name), None | Some(Value::Nil)),
+            Filter::NotNull(name) => !matches!(field(name), None | Some(Value::Nil)),
+            Filter::And(left, right) => left.matches(fields) && right.matches(fields),
+            Filter::Or(left, right) => left.matches(fields) || right.matches(fields)
+        }
+    }
+
+    /// matches_datalog()
+    ///
+    /// Decodes a datalog and checks the filter against it. A datalog that
+    /// can't be decoded never matches.
+    pub fn matches_datalog(&self, datalog: &[u8]) -> bool {
+        match fields(datalog) {
+            Ok(fields) => self.matches(&fields),
+            Err(_) => false
+        }
+    }
+}
+
+/// compare()
+///
+/// Orders a field's value against a literal, None if they can't be compared.
+/// f32 fields are compared as f32, so `NO2 = 5.1` matches a stored 5.1.
+fn compare(value: &Value, literal: &Literal) -> Option<Ordering> {
+    match (value, literal) {
+        (Value::F32(value), Literal::Number(number)) => value.partial_cmp(&(*number as f32)),
+        (Value::F64(value), Literal::Number(number)) => value.partial_cmp(number),
+        (Value::Int(value), Literal::Number(number)) => (*value as f64).partial_cmp(number),
+        (Value::UInt(value), Literal::Number(number)) => (*value as f64).partial_cmp(number),
+        (Value::String(value), Literal::String(string)) => Some(value.as_str().cmp(string.as_str())),
+        (Value::Bool(value), Literal::Bool(boolean)) => Some(value.cmp(boolean)),
+        _ => None
+    }
+}
+
+/// tokenize()
+///
+/// Splits a filter expression into tokens
+fn tokenize(expression: &str) -> Result<Vec<Token>, io::Error> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => { chars.next(); },
+            '(' => { chars.next(); tokens.push(Token::Open); },
+            ')' => { chars.next(); tokens.push(Token::Close); },
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let equals = chars.peek() == Some(&'=');
+                if equals {
+                    chars.next();
+                }
+                let comparison = match (c, equals) {
+                    ('=', _) => Comparison::Eq,
+                    ('!', true) => Comparison::Ne,
+                    ('<', false) => Comparison::Lt,
+                    ('<', true) => Comparison::Le,
+                    ('>', false) => Comparison::Gt,
+                    ('>', true) => Comparison::Ge,
+                    _ => return Err(invalid_filter("'!' has to be followed by '='".to_string()))
+                };
+                tokens.push(Token::Comparison(comparison));
+            },
+            '\'' | '"' => {
+                chars.next();
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some(end) if end == c => break,
+                        Some(next) => string.push(next),
+                        None => return Err(invalid_filter("unterminated string".to_string()))
+                    }
+                }
+                tokens.push(Token::Literal(Literal::String(string)));
+            },
+            _ => {
+                let mut word = String::new();
+                while let Some(&next) = chars.peek() {
+                    if next.is_alphanumeric() || next == '_' || next == '.' || next == '-' || next == '+' {
+                        word.push(next);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                if word.is_empty() {
+                    return Err(invalid_filter(format!("unexpected {:?}", c)));
+                }
+                let token = match word.to_lowercase().as_str() {
+                    "true" => Token::Literal(Literal::Bool(true)),
+                    "false" => Token::Literal(Literal::Bool(false)),
+                    _ => match word.parse::<f64>() {
+                        Ok(number) if word.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '+' || c == '.') => Token::Literal(Literal::Number(number)),
+                        _ => Token::Word(word)
+                    }
+                };
+                tokens.push(token);
+            }
+        }
+    }
+    return Ok(tokens);
+}
+
+/// parse_or()
+///
+/// or-expression := and-expression ('or' and-expression)*
+fn parse_or(tokens: &[Token], position: &mut usize, depth: usize) -> Result<Filter, io::Error> {
+    let mut filter = parse_and(tokens, position, depth)?;
+    while is_keyword(tokens.get(*position), "or") {
+        *position += 1;
+        filter = Filter::Or(Box::new(filter), Box::new(parse_and(tokens, position, depth)?));
+    }
+    return Ok(filter);
+}
+
+/// parse_and()
+///
+/// and-expression := condition ('and' condition)*
+fn parse_and(tokens: &[Token], position: &mut usize, depth: usize) -> Result<Filter, io::Error> {
+    let mut filter = parse_condition(tokens, position, depth)?;
+    while is_keyword(tokens.get(*position), "and") {
+        *position += 1;
+        filter = Filter::And(Box::new(filter), Box::new(parse_condition(tokens, position, depth)?));
+    }
+    return Ok(filter);
+}
+
+/// parse_condition()
+///
+/// condition := '(' or-expression ')' | field 'is' ['not'] 'null' | field comparison literal
+fn parse_condition(tokens: &[Token], position: &mut usize, depth: usize) -> Result<Filter, io::Error> {
+    let token = tokens.get(*position).ok_or_else(|| invalid_filter("expression ends early".to_string()))?;
+    *position += 1;
+    let field = match token {
+        Token::Open => {
+            if depth >= MAX_FILTER_DEPTH {
+                return Err(invalid_filter("parentheses are nested too deep".to_string()));
+            }
+            let filter = parse_or(tokens, position, depth + 1)?;
+            if tokens.get(*position) != Some(&Token::Close) {
+                return Err(invalid_filter("missing ')'".to_string()));
+            }
+            *position += 1;
+            return Ok(filter);
+        },
+        Token::Word(word) if !["and", "or", "is", "not", "null"].contains(&word.to_lowercase().as_str()) => word.clone(),
+        token => return Err(invalid_filter(format!("expected a field, found {:?}", token)))
+    };
+
+    match tokens.get(*position) {
+        Some(token) if is_keyword(Some(token), "is") => {
+            *position += 1;
+            let not = is_keyword(tokens.get(*position), "not");
+            if not {
+                *position += 1;
+            }
+            if !is_keyword(tokens.get(*position), "null") {
+                return Err(invalid_filter(format!("expected 'null' after 'is' for {}", field)));
+            }
+            *position += 1;
+            return Ok(if not { Filter::NotNull(field) } else { Filter::IsNull(field) });
+        },
+        Some(Token::Comparison(comparison)) => {
+            *position += 1;
+            match tokens.get(*position) {
+                Some(Token::Literal(literal)) => {
+                    *position += 1;
+                    return Ok(Filter::Compare(field, *comparison, literal.clone()));
+                },
+                Some(token) if is_keyword(Some(token), "null") => Err(invalid_filter(format!("use '{} is null' to check for null", field))),
+                token => Err(invalid_filter(format!("expected a value after the comparison for {}, found {:?}", field, token)))
+            }
+        },
+        token => Err(invalid_filter(format!("expected a comparison or 'is' after {}, found {:?}", field, token)))
+    }
+}
+
+/// is_keyword()
+///
+/// Checks if a token is the given keyword, in any case
+fn is_keyword(token: Option<&Token>, keyword: &str) -> bool {
+    match token {
+        Some(Token::Word(word)) => word.eq_ignore_ascii_case(keyword),
+        _ => false
+    }
+}
+
+/// invalid_filter()
+///
+/// Creates the error for a filter expression that can't be parsed
+fn invalid_filter(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid filter: {}", reason))
+}
+
+/// Bucket
+///
+/// Statistics of the numeric fields of the records in a time bucket.
+/// Buckets start at multiples of their length since the Unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Bucket {
+    pub start:      u32,
+    pub records:    u64,    // Records in the bucket, also those without numeric fields
+    pub fields:     BTreeMap<String, FieldStats>,
+}
+
+/// FieldStats
+///
+/// Statistics of a numeric field, nil values are left out
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct FieldStats {
+    pub count:      u64,
+    pub min:        f64,
+    pub max:        f64,
+    pub mean:       f64,
+    pub last:       f64,    // Value of the latest record
+}
+
+/// Aggregator
+///
+/// Groups the records of a cursor into fixed time buckets and returns
+/// the statistics of each bucket that has records, in order
+#[derive(Debug)]
+pub struct Aggregator {
+    cursor:         MyCursor,
+    bucket_secs:    u32,
+    fields:         Vec<String>,
+    pending:        Option<(MpdRecordType, Option<Continuation>)>,  // First record of the next bucket
+    position:       Option<Continuation>,   // After the last record of the last bucket returned
+}
+
+impl Bucket {
+    /// Constructor
+    pub fn new(start: u32) -> Bucket {
+        Bucket {
+            start,
+            records: 0,
+            fields: BTreeMap::new()
+        }
+    }
+
+    /// add()
+    ///
+    /// Adds the numeric fields of a datalog, only those in 'names' unless it is empty
+    pub fn add(&mut self, datalog: &[u8], names: &[String]) -> Result<(), io::Error> {
+        let fields = fields(datalog)?;
+        self.records += 1;
+        for (name, value) in fields {
+            let value = match value {
+                Value::Int(value) => value as f64,
+                Value::UInt(value) => value as f64,
+                Value::F32(value) => f64::from(value),
+                Value::F64(value) => value,
+                _ => continue
+            };
+            if !names.is_empty() && !names.contains(&name) {
+                continue;
+            }
+            let stats = self.fields.entry(name).or_insert(FieldStats {
+                count:  0,
+                min:    value,
+                max:    value,
+                mean:   0.0,
+                last:   value
+            });
+            stats.count += 1;
+            stats.min = stats.min.min(value);
+            stats.max = stats.max.max(value);
+            stats.mean += (value - stats.mean) / stats.count as f64;
+            stats.last = value;
+        }
+        Ok(())
+    }
+
+    /// to_record()
+    ///
+    /// Encodes the bucket as a record with the start as its id
+    pub fn to_record(&self) -> Result<MpdRecordType, io::Error> {
+        let datalog = rmps::to_vec_named(self).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        Ok(MpdRecordType::new(self.start, datalog))
+    }
+
+    /// from_record()
+    ///
+    /// Decodes a bucket encoded by 'to_record()'
+    pub fn from_record(record: &MpdRecordType) -> Result<Bucket, io::Error> {
+        rmps::from_slice(&record.datalog).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
+
+impl Aggregator {
+    /// Constructor
+    ///
+    /// Aggregates the numeric fields in 'fields', or all of them if it is empty
+    pub fn new(cursor: MyCursor, bucket_secs: u32, fields: Vec<String>) -> Result<Aggregator, io::Error> {
+        if bucket_secs == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Buckets have to be at least a second long"));
+        }
+        Ok(Aggregator {
+            cursor,
+            bucket_secs,
+            fields,
+            pending: None,
+            position: None
+        })
+    }
+
+    /// continuation()
+    ///
+    /// Returns where the cursor stopped after the last bucket, resuming
+    /// from it gives the buckets after that one
+    pub fn continuation(&self) -> Option<Continuation> {
+        self.position.clone()
+    }
+
+    /// read()
+    ///
+    /// Reads the next record along with the continuation after it
+    fn read(&mut self) -> Option<(MpdRecordType, Option<Continuation>)> {
+        let mut record: Option<MpdRecordType> = None;
+        self.cursor.next(&mut record);
+        let record = record?;
+        Some((record, self.cursor.continuation()))
+    }
+}
+
+impl Iterator for Aggregator {
+    type Item = Bucket;
+
+    fn next(&mut self) -> Option<Bucket> {
+        let (record, mut position) = match self.pending.take() {
+            Some(pending) => pending,
+            None => self.read()?
+        };
+        let start = record.id - record.id % self.bucket_secs;
+        let mut bucket = Bucket::new(start);
+        let mut record = record;
+        loop {
+            if let Err(error) = bucket.add(&record.datalog, &self.fields) {
+                error!("Could not aggregate record {}! {:?}", record.id, error);
+            }
+            match self.read() {
+                Some((next, next_position)) if next.id - next.id % self.bucket_secs == start => {
+                    record = next;
+                    position = next_position;
+                },
+                next => {
+                    self.pending = next;
+                    break;
+                }
+            }
+        }
+        self.position = position;
+        return Some(bucket);
+    }
+}
+
+#[cfg(test)]
+mod query_tests {
+    use super::*;
+    use crate::database::{self, RawData};
+    use crate::table;
+
+    #[test]
+    fn test_project() {
+        let raw = RawData{AQHI: Some(3), PM2_5: Some(12.5), T: Some(21.0), ..Default::default()};
+        let names = vec!["PM2_5".to_string(), "T".to_string(), "RH".to_string(), "missing".to_string()];
+        let expected = Value::Map(vec![
+            (Value::String("PM2_5".to_string()), Value::F32(12.5)),
+            (Value::String("T".to_string()), Value::F32(21.0)),
+            (Value::String("RH".to_string()), Value::Nil),
+            (Value::String("missing".to_string()), Value::Nil)
+        ]);
+
+        // Records with and without field names
+        for datalog in [table::encode(&raw).unwrap(), database::serialize_struct(&raw).unwrap()] {
+            let projected = project(&datalog, &names).unwrap();
+            assert_eq!(rmps::from_slice::<Value>(&projected).unwrap(), expected);
+        }
+        assert_eq!(project(&rmps::to_vec(&5).unwrap(), &names).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(project(&[0xc1], &names).is_err());
+        assert!(project(&vec![0x91; 100000], &names).is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let raw = RawData{AQHI: None, NO2: Some(5.1), T: Some(-12.0), AQI: Some(40), TimeStamp: Some("noon".to_string()), ..Default::default()};
+        let named = fields(&table::encode(&raw).unwrap()).unwrap();
+        let matches = |expression: &str| Filter::parse(expression).unwrap().matches(&named);
+
+        assert!(matches("NO2 > 5.0"));
+        assert!(matches("NO2 = 5.1"));
+        assert!(!matches("NO2 != 5.1"));
+        assert!(matches("AQI >= 40 and AQI<=40"));
+        assert!(matches("T <= -10"));
+        assert!(matches("TimeStamp = 'noon' and TimeStamp != \"night\""));
+        assert!(matches("AQHI is null and NO2 IS NOT NULL and missing is null"));
+        assert!(!matches("AQHI > 0 or AQHI <= 0"));  // Null fails every comparison
+        assert!(!matches("TimeStamp > 5"));  // So does another type
+        assert!(matches("NO2 < 1 or NO2 > 5 and T < 0"));  // and binds tighter
+        assert!(!matches("(NO2 < 1 or NO2 > 5) and T > 0"));
+
+        // The array encoding is filtered by the same names
+        assert!(Filter::parse("NO2 > 5.0").unwrap().matches_datalog(&database::serialize_struct(&raw).unwrap()));
+        assert!(!Filter::parse("NO2 is null").unwrap().matches_datalog(&[0xc1]));
+
+        for invalid in ["", "NO2", "NO2 >", "NO2 > 5 and", "NO2 = null", "NO2 ! 5", "(NO2 > 5", "NO2 > 5)", "T = 'open", "and > 5", "NO2 is 5", "NO2 > 5 T < 3"] {
+            assert_eq!(Filter::parse(invalid).unwrap_err().kind(), io::ErrorKind::InvalidInput, "{}", invalid);
+        }
+        assert!(Filter::parse(&format!("{}T > 0{}", "(".repeat(100), ")".repeat(100))).is_err());
+    }
+
+    #[test]
+    fn test_filter_cursor() {
+        let database = crate::Database::in_memory();
+        let table: table::Table<RawData> = database.table("levels");
+        table.insert(1577916000, &RawData{NO2: Some(2.0), ..Default::default()}).unwrap();
+        table.insert(1577916060, &RawData{NO2: Some(7.5), ..Default::default()}).unwrap();
+        table.insert(1577919600, &RawData{NO2: None, ..Default::default()}).unwrap();
+        table.insert(1577919660, &RawData{NO2: Some(9.0), ..Default::default()}).unwrap();
+
+        let ids = |expression: &str| {
+            let mut cursor = database.get_data("levels", 1577916000, 1577923199).unwrap();
+            cursor.set_filter(Filter::parse(expression).unwrap());
+            let mut ids = Vec::new();
+            while let Some(record) = cursor.try_next().unwrap() {
+                ids.push(record.id);
+            }
+            ids
+        };
+        assert_eq!(ids("NO2 > 5.0"), vec![1577916060, 1577919660]);
+        assert_eq!(ids("NO2 is null"), vec![1577919600]);
+        assert_eq!(ids("NO2 > 100"), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let database = crate::Database::in_memory();
+        let table: table::Table<RawData> = database.table("levels");
+        // 2020-01-01 22:00, two records in the first 5 minutes, one in the third, none in between
+        table.insert(1577916000, &RawData{NO2: Some(2.0), AQI: Some(10), T: None, ..Default::default()}).unwrap();
+        table.insert(1577916299, &RawData{NO2: Some(6.0), AQI: Some(20), T: Some(1.5), ..Default::default()}).unwrap();
+        table.insert(1577916600, &RawData{NO2: Some(4.0), TimeStamp: Some("x".to_string()), ..Default::default()}).unwrap();
+
+        let buckets: Vec<Bucket> = database.aggregate("levels", 1577916000, 1577919599, 300, Vec::new()).unwrap().collect();
+        assert_eq!(buckets.iter().map(|bucket| (bucket.start, bucket.records)).collect::<Vec<(u32, u64)>>(), vec![(1577916000, 2), (1577916600, 1)]);
+        assert_eq!(buckets[0].fields["NO2"], FieldStats{count: 2, min: 2.0, max: 6.0, mean: 4.0, last: 6.0});
+        assert_eq!(buckets[0].fields["AQI"], FieldStats{count: 2, min: 10.0, max: 20.0, mean: 15.0, last: 20.0});
+        assert_eq!(buckets[0].fields["T"].count, 1);  // Nil is left out
+        assert_eq!(buckets[1].fields.keys().collect::<Vec<&String>>(), vec!["NO2"]);  // So are strings
+
+        // Only some fields, an hour at a time
+        let buckets: Vec<Bucket> = database.aggregate("levels", 1577916000, 1577919599, 3600, vec!["NO2".to_string()]).unwrap().collect();
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets[0].fields.len(), 1);
+        assert_eq!(buckets[0].fields["NO2"].mean, 4.0);
+        assert_eq!(Bucket::from_record(&buckets[0].to_record().unwrap()).unwrap(), buckets[0]);
+        assert_eq!(buckets[0].to_record().unwrap().id, 1577916000);
+
+        // Resuming after the first bucket gives the rest
+        let mut aggregator = database.aggregate("levels", 1577916000, 1577919599, 300, Vec::new()).unwrap();
+        aggregator.next().unwrap();
+        let cursor = database.resume(&aggregator.continuation().unwrap(), 1577919599).unwrap();
+        let rest: Vec<Bucket> = Aggregator::new(cursor, 300, Vec::new()).unwrap().collect();
+        assert_eq!(rest.iter().map(|bucket| bucket.start).collect::<Vec<u32>>(), vec![1577916600]);
+
+        assert_eq!(database.aggregate("levels", 1577916000, 1577919599, 0, Vec::new()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_raw_data_fields() {
+        // Names of the array encoding match the fields of RawData
+        let named = fields(&table::encode(&RawData::default()).unwrap()).unwrap();
+        assert_eq!(named.iter().map(|(name, _)| name.as_str()).collect::<Vec<&str>>(), RAW_DATA_FIELDS);
+    }
+}
@@ -0,0 +1,187 @@
+use std::io;
+use std::collections::BTreeSet;
+use log::info;
+
+use crate::database::{self, Database};
+
+/// Rollup
+///
+/// A table kept up to date with the `query::Bucket`s of another, e.g.
+/// 'levels_1h' holding the hourly stats of 'levels'. Its records are the
+/// buckets (id is the start of the bucket), read like any other table.
+///
+/// Buckets either divide an hour or are whole hours, so every shard of the
+/// rollup covers whole shards of the source: its hour for buckets of up to
+/// an hour, its bucket for longer ones. A rollup shard is always rebuilt as
+/// a whole from the source records it covers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rollup {
+    pub table:          String,
+    pub source:         String,
+    pub bucket_secs:    u32,
+    pub fields:         Vec<String>,    // Only these fields of the source, every numeric one if empty
+}
+
+impl Rollup {
+    /// Constructor
+    pub fn new(table: &str, source: &str, bucket_secs: u32, fields: Vec<String>) -> Result<Rollup, io::Error> {
+        database::validate_table_name(table)?;
+        database::validate_table_name(source)?;
+        if table == source {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "A rollup can't be its own source"));
+        }
+        if bucket_secs == 0 || (3600 % bucket_secs != 0 && bucket_secs % 3600 != 0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("bucket_secs {} has to divide an hour or be whole hours", bucket_secs)));
+        }
+        Ok(Rollup {
+            table:  table.to_string(),
+            source: source.to_string(),
+            bucket_secs,
+            fields
+        })
+    }
+
+    /// span()
+    ///
+    /// Returns how many seconds of the source a shard of the rollup covers
+    pub fn span(&self) -> i64 {
+        return std::cmp::max(3600, i64::from(self.bucket_secs));
+    }
+
+    /// span_of()
+    ///
+    /// Returns the start of the rollup shard covering a timestamp
+    pub fn span_of(&self, timestamp: i64) -> i64 {
+        return timestamp - timestamp.rem_euclid(self.span());
+    }
+
+    /// rebuild()
+    ///
+    /// Replaces the rollup shard starting at 'start' with the buckets of the
+    /// source records it covers, removing it if there are none. Returns the buckets written.
+    pub fn rebuild(&self, database: &Database, start: i64) -> Result<usize, io::Error> {
+        database.check_writable()?;
+        let start = self.span_of(start);
+        let end = start + self.span() - 1;
+        if start < 0 || end > i64::from(u32::MAX) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is out of range", start)));
+        }
+
+        // Read the source before locking the rollup
+        let mut buf = Vec::new();
+        let mut buckets = 0;
+        for bucket in database.aggregate(&self.source, start as u32, end as u32, self.bucket_secs, self.fields.clone())? {
+            let serialized_data = database::serialize_struct(bucket.to_record()?)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Could not serialize bucket"))?;
+            buf.extend_from_slice(&serialized_data);
+            buckets += 1;
+        }
+
+        // Readers see either the old shard or the new one
+        let shard = database::get_datetime(start as u32);
+        let lock = database.table_lock(&self.table);
+        let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if database.storage().read_shard(&self.table, &shard)?.is_some() {
+            database.storage().delete_shard(&self.table, &shard)?;
+        }
+        if !buf.is_empty() {
+            database.storage().append(&self.table, &shard, &buf)?;
+        }
+        info!("Rolled up {:?} into {:?} at {:?}, {} buckets", self.source, self.table, shard, buckets);
+        return Ok(buckets);
+    }
+
+    /// backfill()
+    ///
+    /// Rebuilds every rollup shard covering 'start_time' to 'end_time' that has
+    /// source records or rollup records. Returns the buckets written.
+    pub fn backfill(&self, database: &Database, start_time: u32, end_time: u32) -> Result<usize, io::Error> {
+        let first = self.span_of(i64::from(start_time));
+        let last = self.span_of(i64::from(end_time));
+        let mut spans = BTreeSet::new();
+        for table in [&self.source, &self.table].iter() {
+            for shard in database.storage().list_shards(table)? {
+                let span = self.span_of(shard.timestamp());
+                if span >= first && span <= last {
+                    spans.insert(span);
+                }
+            }
+        }
+
+        let mut buckets = 0;
+        for span in spans {
+            buckets += self.rebuild(database, span)?;
+        }
+        return Ok(buckets);
+    }
+}
+
+#[cfg(test)]
+mod rollup_tests {
+    use super::*;
+    use crate::database::{MpdRecordType, RawData};
+    use crate::query::Bucket;
+    use crate::table::Table;
+
+    /// buckets()
+    ///
+    /// Every bucket stored in a rollup table
+    fn buckets(database: &Database, table: &str) -> Vec<Bucket> {
+        let mut cursor = database.get_data(table, 0, u32::MAX).unwrap();
+        let mut buckets = Vec::new();
+        while let Some(record) = cursor.try_next().unwrap() {
+            buckets.push(Bucket::from_record(&record).unwrap());
+        }
+        return buckets;
+    }
+
+    #[test]
+    fn test_rollup_new() {
+        assert!(Rollup::new("levels_1h", "levels", 3600, Vec::new()).is_ok());
+        assert!(Rollup::new("levels_5m", "levels", 300, Vec::new()).is_ok());
+        assert!(Rollup::new("levels_1d", "levels", 86400, Vec::new()).is_ok());
+        for (table, source, bucket_secs) in [("levels", "levels", 3600), ("levels_7m", "levels", 420), ("levels_90m", "levels", 5400), ("levels_0", "levels", 0), ("../up", "levels", 3600)].iter() {
+            assert_eq!(Rollup::new(table, source, *bucket_secs, Vec::new()).unwrap_err().kind(), io::ErrorKind::InvalidInput, "{}", table);
+        }
+
+        let rollup = Rollup::new("levels_1d", "levels", 86400, Vec::new()).unwrap();
+        assert_eq!(rollup.span_of(1577923199), 1577836800);  // 2020-01-01 23:59:59 is in the 2020-01-01 bucket
+        assert_eq!(Rollup::new("levels_5m", "levels", 300, Vec::new()).unwrap().span_of(1577923199), 1577919600);
+    }
+
+    #[test]
+    fn test_rollup_backfill() {
+        let database = Database::in_memory();
+        let levels: Table<RawData> = database.table("levels");
+        // 2020-01-01 22:00, 22:30, 23:00 and 2020-01-02 00:00
+        for (id, no2) in [(1577916000, 1.0), (1577917800, 3.0), (1577919600, 8.0), (1577923200, 4.0)].iter() {
+            levels.insert(*id, &RawData{NO2: Some(*no2), ..Default::default()}).unwrap();
+        }
+
+        let hourly = Rollup::new("levels_1h", "levels", 3600, vec!["NO2".to_string()]).unwrap();
+        let daily = Rollup::new("levels_1d", "levels", 86400, vec!["NO2".to_string()]).unwrap();
+        assert_eq!(hourly.backfill(&database, 0, u32::MAX).unwrap(), 3);
+        assert_eq!(daily.backfill(&database, 0, u32::MAX).unwrap(), 2);
+
+        assert_eq!(buckets(&database, "levels_1h"), database.aggregate("levels", 0, u32::MAX, 3600, vec!["NO2".to_string()]).unwrap().collect::<Vec<Bucket>>());
+        let days = buckets(&database, "levels_1d");
+        assert_eq!(days.iter().map(|bucket| (bucket.start, bucket.records)).collect::<Vec<(u32, u64)>>(), vec![(1577836800, 3), (1577923200, 1)]);
+        assert_eq!(days[0].fields["NO2"].mean, 4.0);
+        assert_eq!(days[0].fields["NO2"].max, 8.0);
+
+        // Backfilling again replaces the buckets instead of adding to them
+        assert_eq!(hourly.backfill(&database, 1577916000, 1577919599).unwrap(), 1);
+        assert_eq!(buckets(&database, "levels_1h").len(), 3);
+
+        // A rollup shard whose source is gone is removed
+        database.delete_file("levels", "20200101/22").unwrap();
+        levels.insert(1577919601, &RawData{NO2: Some(10.0), ..Default::default()}).unwrap();
+        assert_eq!(hourly.backfill(&database, 1577916000, 1577923199).unwrap(), 1);
+        let hours = buckets(&database, "levels_1h");
+        assert_eq!(hours.iter().map(|bucket| (bucket.start, bucket.records)).collect::<Vec<(u32, u64)>>(), vec![(1577919600, 2), (1577923200, 1)]);
+
+        // Records of the rollup are plain records
+        let record: MpdRecordType = database.get_data("levels_1h", 1577919600, 1577919600).unwrap().try_next().unwrap().unwrap();
+        assert_eq!(record.id, 1577919600);
+    }
+}
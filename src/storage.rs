@@ -0,0 +1,502 @@
+use std::io;
+use std::io::prelude::*;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::fs::create_dir;
+use std::fs::OpenOptions;
+use std::fs::remove_file;
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI64, Ordering};
+use chrono::prelude::*;
+use chrono::Duration;
+
+use crate::database::{self, DATE_FORMAT, TIME_FORMAT};
+
+/// Storage
+///
+/// Where the hour files (shards) of every table are kept. A shard is
+/// named by the start of its hour and holds MsgPack records back to back.
+pub trait Storage: Send + Sync + fmt::Debug {
+    /// Append bytes to the end of a shard, creating it if needed
+    fn append(&self, table: &str, shard: &DateTime<Utc>, data: &[u8]) -> Result<(), io::Error>;
+
+    /// Open a shard to append to it repeatedly, creating it if needed
+    fn appender(&self, table: &str, shard: &DateTime<Utc>) -> Result<Box<dyn Appender>, io::Error>;
+
+    /// Lists every table
+    fn list_tables(&self) -> Result<Vec<String>, io::Error>;
+
+    /// Lists every shard of a table, oldest first
+    fn list_shards(&self, table: &str) -> Result<Vec<DateTime<Utc>>, io::Error>;
+
+    /// Read a whole shard, None if it doesn't exist
+    fn read_shard(&self, table: &str, shard: &DateTime<Utc>) -> Result<Option<Vec<u8>>, io::Error>;
+
+    /// Remove a shard
+    fn delete_shard(&self, table: &str, shard: &DateTime<Utc>) -> Result<(), io::Error>;
+
+    /// Bytes used by every shard of every table
+    fn size(&self) -> Result<u64, io::Error> {
+        let mut size = 0;
+        for table in self.list_tables()? {
+            for shard in self.list_shards(&table)? {
+                size += self.read_shard(&table, &shard)?.map_or(0, |buf| buf.len() as u64);
+            }
+        }
+        return Ok(size);
+    }
+
+    /// Find the first shard of a table between 'from' and 'to' (inclusive)
+    fn next_shard(&self, table: &str, from: &DateTime<Utc>, to: &DateTime<Utc>) -> Result<Option<DateTime<Utc>>, io::Error> {
+        Ok(self.list_shards(table)?.into_iter().find(|shard| shard >= from && shard <= to))
+    }
+}
+
+/// Appender
+///
+/// An open shard returned by `Storage::appender()`
+pub trait Appender: Write + Send + fmt::Debug {}
+
+impl<T: Write + Send + fmt::Debug> Appender for T {}
+
+/// FsStorage
+///
+/// Keeps shards as files: `<source>/<table>/<YYYYmmdd>/<HH>`
+#[derive(Debug, Clone)]
+pub struct FsStorage {
+    pub source:     PathBuf,
+    bytes:          Arc<FsBytes>,
+}
+
+/// FsBytes
+///
+/// What a `FsStorage` and its clones have written and removed, and the size
+/// of every shard once measured. Writes don't wait for the measurement.
+#[derive(Debug, Default)]
+struct FsBytes {
+    counted:    AtomicI64,                  // Bytes written minus bytes removed
+    measured:   Mutex<Option<(u64, i64)>>,  // Size of every shard, and 'counted' before measuring
+}
+
+/// FsAppender
+///
+/// An open shard of a `FsStorage`, counting what is written to it
+#[derive(Debug)]
+struct FsAppender {
+    file:       File,
+    bytes:      Arc<FsBytes>,
+}
+
+/// Shards of a `MemoryStorage` by (table, hour)
+type MemoryShards = Arc<Mutex<BTreeMap<(String, DateTime<Utc>), Vec<u8>>>>;
+
+/// MemoryStorage
+///
+/// Keeps shards in memory, for tests and simulations
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    shards:     MemoryShards,
+}
+
+/// MemoryAppender
+///
+/// An open shard of a `MemoryStorage`
+#[derive(Debug)]
+struct MemoryAppender {
+    shards:     MemoryShards,
+    key:        (String, DateTime<Utc>),
+}
+
+impl FsStorage {
+    /// Constructor
+    pub fn new<P: AsRef<Path>>(source: P) -> FsStorage {
+        FsStorage {
+            source: source.as_ref().to_path_buf(),
+            bytes:  Arc::new(FsBytes::default())
+        }
+    }
+
+    /// shard_directory()
+    ///
+    /// Returns the directory holding a table's hour files for the day of 'shard'
+    pub fn shard_directory(&self, table: &str, shard: &DateTime<Utc>) -> PathBuf {
+        self.source.join(table).join(shard.format(DATE_FORMAT).to_string())
+    }
+
+    /// shard_file()
+    ///
+    /// Returns the hour file of a table for 'shard'
+    pub fn shard_file(&self, table: &str, shard: &DateTime<Utc>) -> PathBuf {
+        self.shard_directory(table, shard).join(shard.format(TIME_FORMAT).to_string())
+    }
+
+    /// resolve()
+    ///
+    /// Resolves an existing path relative to the source, making sure it
+    /// doesn't end up outside of the source (e.g. through a symlink)
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, io::Error> {
+        database::validate_relative_path(path)?;
+        let resolved = fs::canonicalize(self.source.join(path))?;
+        if !resolved.starts_with(fs::canonicalize(&self.source)?) {
+            return Err(database::invalid_name(path, "path resolves outside of the database source"));
+        }
+        return Ok(resolved);
+    }
+
+    /// resolve_new()
+    ///
+    /// Like resolve(), for a path that may not exist yet. Missing directories
+    /// are created one at a time, each checked before anything is made in it,
+    /// while the last component is left for the caller to create.
+    fn resolve_new(&self, path: &str) -> Result<PathBuf, io::Error> {
+        database::validate_relative_path(path)?;
+        let mut resolved = fs::canonicalize(&self.source)?;
+        let names: Vec<&str> = path.split('/').collect();
+        for (index, name) in names.iter().enumerate() {
+            let partial = names[..=index].join("/");
+
+            // Anything already there, including a dangling symlink, has to resolve inside
+            if fs::symlink_metadata(self.source.join(&partial)).is_ok() {
+                resolved = self.resolve(&partial)?;
+                continue;
+            }
+            if index + 1 == names.len() {
+                resolved = resolved.join(name);
+                continue;
+            }
+            match create_dir(resolved.join(name)) {
+                Err(error) if error.kind() != io::ErrorKind::AlreadyExists => return Err(error),
+                _ => resolved = self.resolve(&partial)?
+            }
+        }
+        return Ok(resolved);
+    }
+
+    /// open_append()
+    ///
+    /// Opens a shard at its end, creating the file and its directory if needed
+    fn open_append(&self, table: &str, shard: &DateTime<Utc>) -> Result<File, io::Error> {
+        let file = self.resolve_new(&shard_path(table, shard))?;
+        OpenOptions::new().create(true).append(true).open(file)
+    }
+
+    /// measure()
+    ///
+    /// Adds up the file sizes of every shard, skipping those removed meanwhile
+    fn measure(&self) -> Result<u64, io::Error> {
+        let mut size = 0;
+        for table in self.list_tables()? {
+            for shard in unless_not_found(self.list_shards(&table), Vec::new())? {
+                let file = self.resolve(&shard_path(&table, &shard)).and_then(fs::metadata);
+                size += unless_not_found(file.map(|metadata| metadata.len()), 0)?;
+            }
+        }
+        return Ok(size);
+    }
+}
+
+impl Storage for FsStorage {
+    fn append(&self, table: &str, shard: &DateTime<Utc>, data: &[u8]) -> Result<(), io::Error> {
+        let mut appender = FsAppender {
+            file:   self.open_append(table, shard)?,
+            bytes:  self.bytes.clone()
+        };
+        appender.write_all(data)
+    }
+
+    fn appender(&self, table: &str, shard: &DateTime<Utc>) -> Result<Box<dyn Appender>, io::Error> {
+        Ok(Box::new(FsAppender {
+            file:   self.open_append(table, shard)?,
+            bytes:  self.bytes.clone()
+        }))
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, io::Error> {
+        let mut tables = list_names(&self.source, true)?;
+        tables.retain(|table| database::validate_table_name(table).is_ok());
+        return Ok(tables);
+    }
+
+    fn list_shards(&self, table: &str) -> Result<Vec<DateTime<Utc>>, io::Error> {
+        let mut shards = Vec::new();
+        if !self.source.join(table).is_dir() {
+            return Ok(shards);
+        }
+        let directory = self.resolve(table)?;
+        for date in list_names(&directory, true)? {
+            for hour in list_names(&directory.join(&date), false)? {
+                if let Some(shard) = parse_shard(&date, &hour) {
+                    shards.push(shard);
+                }
+            }
+        }
+        shards.sort();
+        return Ok(shards);
+    }
+
+    fn read_shard(&self, table: &str, shard: &DateTime<Utc>) -> Result<Option<Vec<u8>>, io::Error> {
+        if !self.shard_file(table, shard).exists() {
+            return Ok(None);
+        }
+        let file = self.resolve(&shard_path(table, shard))?;
+        let mut buf = Vec::new();
+        File::open(file)?.read_to_end(&mut buf)?;
+        return Ok(Some(buf));
+    }
+
+    fn delete_shard(&self, table: &str, shard: &DateTime<Utc>) -> Result<(), io::Error> {
+        let file = self.resolve(&shard_path(table, shard))?;
+        let len = fs::metadata(&file)?.len();
+        remove_file(file)?;
+        self.bytes.counted.fetch_sub(len as i64, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Goes by file sizes instead of reading the shards, measured only the
+    /// first time and then adding what was written or removed since. Files
+    /// changed by anything else than this storage (or its clones) aren't
+    /// noticed afterwards, and a write made while measuring may count twice.
+    fn size(&self) -> Result<u64, io::Error> {
+        let mut measured = self.bytes.measured.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (size, counted) = match *measured {
+            Some(measured) => measured,
+            None => {
+                let counted = self.bytes.counted.load(Ordering::SeqCst);
+                let size = self.measure()?;
+                *measured = Some((size, counted));
+                (size, counted)
+            }
+        };
+        return Ok(std::cmp::max(size as i64 + self.bytes.counted.load(Ordering::SeqCst) - counted, 0) as u64);
+    }
+
+    /// Walks hour by hour, skipping days without a directory
+    fn next_shard(&self, table: &str, from: &DateTime<Utc>, to: &DateTime<Utc>) -> Result<Option<DateTime<Utc>>, io::Error> {
+        if self.source.join(table).exists() {
+            self.resolve(table)?;
+        }
+        let mut curr_ts = *from;
+        while curr_ts <= *to {
+            // Check if Directory doesn't exist
+            if !self.shard_directory(table, &curr_ts).exists() {
+                // Add a day of time, set hours, minutes and seconds to 0 and continue
+                curr_ts = (curr_ts + Duration::days(1)).date().and_hms(0, 0, 0);
+                continue;
+            }
+
+            // Check if File doesn't exist
+            if !self.shard_file(table, &curr_ts).exists() {
+                curr_ts = curr_ts + Duration::hours(1);
+                continue;
+            }
+            return Ok(Some(curr_ts));
+        }
+        return Ok(None);
+    }
+}
+
+impl MemoryStorage {
+    /// Constructor
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn append(&self, table: &str, shard: &DateTime<Utc>, data: &[u8]) -> Result<(), io::Error> {
+        let mut shards = self.shards.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        shards.entry((table.to_string(), *shard)).or_default().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn appender(&self, table: &str, shard: &DateTime<Utc>) -> Result<Box<dyn Appender>, io::Error> {
+        self.append(table, shard, &[])?;
+        Ok(Box::new(MemoryAppender {
+            shards: self.shards.clone(),
+            key:    (table.to_string(), *shard)
+        }))
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, io::Error> {
+        let shards = self.shards.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut tables: Vec<String> = shards.keys().map(|(table, _)| table.clone()).collect();
+        tables.dedup();
+        return Ok(tables);
+    }
+
+    fn list_shards(&self, table: &str) -> Result<Vec<DateTime<Utc>>, io::Error> {
+        let shards = self.shards.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return Ok(shards.keys().filter(|(name, _)| name == table).map(|(_, shard)| *shard).collect());
+    }
+
+    fn read_shard(&self, table: &str, shard: &DateTime<Utc>) -> Result<Option<Vec<u8>>, io::Error> {
+        let shards = self.shards.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return Ok(shards.get(&(table.to_string(), *shard)).cloned());
+    }
+
+    fn delete_shard(&self, table: &str, shard: &DateTime<Utc>) -> Result<(), io::Error> {
+        let mut shards = self.shards.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match shards.remove(&(table.to_string(), *shard)) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("No shard {:?} in table {:?}", shard, table)))
+        }
+    }
+
+    fn next_shard(&self, table: &str, from: &DateTime<Utc>, to: &DateTime<Utc>) -> Result<Option<DateTime<Utc>>, io::Error> {
+        if from > to {
+            return Ok(None);
+        }
+        let shards = self.shards.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let range = (table.to_string(), *from)..=(table.to_string(), *to);
+        return Ok(shards.range(range).next().map(|((_, shard), _)| *shard));
+    }
+}
+
+impl Write for FsAppender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.bytes.counted.fetch_add(written as i64, Ordering::SeqCst);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Write for MemoryAppender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut shards = self.shards.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        shards.entry(self.key.clone()).or_default().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// shard_path()
+///
+/// Returns the path of a shard relative to the source, e.g. "levels/20200101/22"
+fn shard_path(table: &str, shard: &DateTime<Utc>) -> String {
+    return format!("{}/{}/{}", table, shard.format(DATE_FORMAT), shard.format(TIME_FORMAT));
+}
+
+/// unless_not_found()
+///
+/// Replaces a NotFound error with 'default', for files removed while walking the storage
+fn unless_not_found<T>(result: Result<T, io::Error>, default: T) -> Result<T, io::Error> {
+    match result {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(default),
+        result => return result
+    }
+}
+
+/// list_names()
+///
+/// Lists the names of the directories (or files) in a directory, sorted
+fn list_names(path: &Path, directories: bool) -> Result<Vec<String>, io::Error> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(path)?.flatten() {
+        if entry.path().is_dir() != directories {
+            continue;
+        }
+        if let Ok(name) = entry.file_name().into_string() {
+            names.push(name);
+        }
+    }
+    names.sort();
+    return Ok(names);
+}
+
+/// parse_shard()
+///
+/// Converts a date directory and hour file name back to the start of the hour
+pub fn parse_shard(date: &str, hour: &str) -> Option<DateTime<Utc>> {
+    if date.len() != 8 || hour.len() != 2 || !hour.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let date = NaiveDate::parse_from_str(date, DATE_FORMAT).ok()?;
+    let hour = hour.parse::<u32>().ok()?;
+    if hour > 23 {
+        return None;
+    }
+    return Some(DateTime::<Utc>::from_utc(date.and_hms(hour, 0, 0), Utc));
+}
+
+#[cfg(test)]
+mod storage_tests {
+    use super::*;
+
+    /// check_storage()
+    ///
+    /// Runs the same operations against any storage
+    fn check_storage(storage: &dyn Storage, table: &str) {
+        let first = Utc.ymd(2020, 1, 9).and_hms(22, 0, 0);
+        let second = Utc.ymd(2020, 1, 10).and_hms(1, 0, 0);
+
+        assert_eq!(storage.read_shard(table, &first).unwrap(), None);
+        storage.append(table, &second, &[3, 4]).unwrap();
+        storage.append(table, &first, &[1]).unwrap();
+        let mut appender = storage.appender(table, &first).unwrap();
+        appender.write_all(&[2]).unwrap();
+        drop(appender);
+
+        assert!(storage.list_tables().unwrap().contains(&table.to_string()));
+        assert_eq!(storage.list_shards(table).unwrap(), vec![first, second]);
+        assert_eq!(storage.read_shard(table, &first).unwrap(), Some(vec![1, 2]));
+        assert_eq!(storage.size().unwrap(), 4);
+        assert_eq!(storage.next_shard(table, &(first + Duration::hours(1)), &second).unwrap(), Some(second));
+        assert_eq!(storage.next_shard(table, &(first + Duration::hours(1)), &(second - Duration::hours(1))).unwrap(), None);
+
+        storage.delete_shard(table, &first).unwrap();
+        storage.delete_shard(table, &second).unwrap();
+        assert_eq!(storage.list_shards(table).unwrap(), Vec::<DateTime<Utc>>::new());
+        assert!(storage.delete_shard(table, &first).is_err());
+    }
+
+    #[test]
+    fn test_fs_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        check_storage(&FsStorage::new(dir.path()), "storage_fs");
+    }
+
+    #[test]
+    fn test_fs_storage_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FsStorage::new(dir.path());
+        let first = Utc.ymd(2020, 1, 9).and_hms(22, 0, 0);
+        let second = Utc.ymd(2020, 1, 10).and_hms(1, 0, 0);
+        storage.append("storage_size", &first, &[1, 2, 3]).unwrap();
+
+        // A shard that can't be found any more is skipped
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.path().join("storage_size/20200109/gone"), dir.path().join("storage_size/20200109/23")).unwrap();
+        assert_eq!(storage.size().unwrap(), 3);
+
+        // Writes and deletes keep the size up to date without measuring again
+        storage.append("storage_size", &second, &[4, 5]).unwrap();
+        storage.appender("storage_size", &first).unwrap().write_all(&[6]).unwrap();
+        assert_eq!(storage.size().unwrap(), 6);
+        storage.delete_shard("storage_size", &first).unwrap();
+        assert_eq!(storage.size().unwrap(), 2);
+        assert_eq!(storage.clone().size().unwrap(), FsStorage::new(dir.path()).size().unwrap());
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        check_storage(&MemoryStorage::new(), "storage_memory");
+    }
+
+    #[test]
+    fn test_parse_shard() {
+        assert_eq!(parse_shard("20200101", "22"), Some(Utc.ymd(2020, 1, 1).and_hms(22, 0, 0)));
+        assert_eq!(parse_shard("20200101", "24"), None);
+        assert_eq!(parse_shard("2020011", "22"), None);
+        assert_eq!(parse_shard("20200101", ".lock"), None);
+    }
+}
@@ -0,0 +1,117 @@
+use std::io;
+use std::marker::PhantomData;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::database::{Database, MpdRecordType, MyCursor};
+
+/// Table
+///
+/// A handle to a table whose records are all of type 'T'. Records are
+/// encoded to MsgPack on insert and decoded again by its cursor.
+/// The byte-level API of `Database` can still be used on the same table.
+#[derive(Debug, Clone)]
+pub struct Table<T> {
+    database:   Database,
+    name:       String,
+    record:     PhantomData<fn() -> T>,
+}
+
+/// TypedCursor
+///
+/// Cursor over a typed table, yielding each record's id (timestamp) and decoded value
+#[derive(Debug)]
+pub struct TypedCursor<T> {
+    cursor:     MyCursor,
+    record:     PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> Table<T> {
+    /// Constructor
+    pub fn new(database: Database, name: &str) -> Table<T> {
+        Table {
+            database,
+            name:   name.to_string(),
+            record: PhantomData
+        }
+    }
+
+    /// name()
+    ///
+    /// Returns the name of the table
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// insert()
+    ///
+    /// Encodes a record and appends it to the hour file of its id (timestamp)
+    pub fn insert(&self, id: u32, record: &T) -> Result<(), io::Error> {
+        let datalog = encode(record)?;
+        self.database.insert_record(&self.name, MpdRecordType::new(id, datalog))
+    }
+
+    /// get_data()
+    ///
+    /// Grabs the decoded records between two timestamps
+    pub fn get_data(&self, start_time: u32, end_time: u32) -> Result<TypedCursor<T>, io::Error> {
+        Ok(TypedCursor {
+            cursor: self.database.get_data(&self.name, start_time, end_time)?,
+            record: PhantomData
+        })
+    }
+}
+
+impl<T: DeserializeOwned> Iterator for TypedCursor<T> {
+    type Item = Result<(u32, T), io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.cursor.try_next() {
+            Ok(Some(record)) => Some(decode(&record.datalog).map(|value| (record.id, value))),
+            Ok(None) => None,
+            Err(error) => Some(Err(error))
+        }
+    }
+}
+
+/// encode()
+///
+/// Encodes a record to MsgPack, keeping field names so it can be read as a map
+pub fn encode<T: Serialize>(record: &T) -> Result<Vec<u8>, io::Error> {
+    rmps::to_vec_named(record).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+/// decode()
+///
+/// Decodes a record from MsgPack
+pub fn decode<T: DeserializeOwned>(datalog: &[u8]) -> Result<T, io::Error> {
+    rmps::from_slice(datalog).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+#[cfg(test)]
+mod table_tests {
+    use super::*;
+    use crate::database::RawData;
+    use crate::fixtures::TempDatabase;
+
+    #[test]
+    fn test_table_round_trip() {
+        let database = TempDatabase::new();
+        let table: Table<RawData> = database.table("table_raw");
+
+        // 2020-01-08 00:00 and 00:15
+        let first = RawData{AQHI: Some(3), PM2_5: Some(12.5), T: Some(21.0), ..Default::default()};
+        let second = RawData{AQI: Some(40), NO2: Some(5.5), ..Default::default()};
+        table.insert(1578441600, &first).unwrap();
+        table.insert(1578442500, &second).unwrap();
+
+        let records: Vec<(u32, RawData)> = table.get_data(1578441600, 1578445200).unwrap().map(|record| record.unwrap()).collect();
+        assert_eq!(records, vec![(1578441600, first), (1578442500, second)]);
+
+        // Records that aren't RawData are an error, not a panic
+        database.insert_record("table_raw", MpdRecordType::new(1578452500, vec![0xc1])).unwrap();  // 03:01:40
+        let results: Vec<_> = table.get_data(1578452500, 1578452500).unwrap().collect();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_err());
+    }
+}
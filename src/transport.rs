@@ -0,0 +1,434 @@
+use std::io;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::warn;
+
+use rumqtt::{MqttClient, MqttOptions, QoS, Receiver, ReconnectOptions};
+use rumqtt::client::Notification;
+
+/// Transport
+///
+/// How the handler talks to the broker. `MqttTransport` connects to a
+/// real broker, `MockTransport` stands in for one within the process.
+pub trait Transport {
+    /// Publishes a payload to a topic
+    fn publish(&mut self, topic: &str, payload: Vec<u8>) -> Result<(), io::Error>;
+
+    /// Publishes a payload the broker keeps for later subscribers of the topic
+    fn publish_retained(&mut self, topic: &str, payload: Vec<u8>) -> Result<(), io::Error>;
+
+    /// Subscribes to a topic
+    fn subscribe(&mut self, topic: &str) -> Result<(), io::Error>;
+
+    /// Waits up to 'timeout' for the next event from the broker
+    fn receive(&mut self, timeout: Duration) -> Incoming;
+
+    /// Checks without waiting if the broker can be reached. Events
+    /// noticed on the way are kept for 'receive()'.
+    fn is_connected(&mut self) -> bool;
+}
+
+/// Incoming
+///
+/// Events received from the broker
+#[derive(Debug, Clone, PartialEq)]
+pub enum Incoming {
+    Publish(Message),
+    Other(String),      // Anything else the broker sent, e.g. acknowledgements
+    ConnectionLost,     // The broker can't be reached, reconnecting
+    Reconnected,        // Subscriptions of a clean session are gone
+    Timeout,
+    Disconnected,       // Nothing more will be received
+}
+
+/// Message
+///
+/// A payload published to a topic
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub topic:      String,
+    pub payload:    Vec<u8>,
+}
+
+/// MqttTransport
+///
+/// Talks to an MQTT broker through rumqtt. A lost connection is made
+/// again by 'receive()', waiting longer after every failed attempt.
+pub struct MqttTransport {
+    mqtt_options:   MqttOptions,
+    client:         MqttClient,
+    notifications:  Receiver<Notification>,
+    pending:        VecDeque<Incoming>,     // Events noticed by 'is_connected()'
+    connected:      bool,
+    backoff:        Backoff,
+    retry_at:       Instant,                // Next attempt to connect while disconnected
+}
+
+/// Backoff
+///
+/// Waits between attempts to connect, doubling from 'initial' up to 'max'
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backoff {
+    initial:    Duration,
+    max:        Duration,
+    next:       Duration,
+}
+
+/// MockTransport
+///
+/// The handler's end of an in-process broker, see `MockBroker`
+#[derive(Debug)]
+pub struct MockTransport {
+    incoming:   mpsc::Receiver<Message>,
+    shared:     Arc<Mutex<MockShared>>,
+}
+
+/// MockBroker
+///
+/// The test's end of an in-process broker. Messages published here are
+/// delivered to the transport if it subscribed to their topic by the time
+/// it receives them, and everything the transport publishes is kept in order.
+/// Retained payloads are kept per topic instead.
+#[derive(Debug, Clone)]
+pub struct MockBroker {
+    incoming:   Arc<Mutex<Option<mpsc::Sender<Message>>>>,   // None once disconnected
+    shared:     Arc<Mutex<MockShared>>,
+}
+
+/// MockShared
+///
+/// State shared by both ends of the in-process broker
+#[derive(Debug, Default)]
+struct MockShared {
+    subscriptions:  Vec<String>,
+    published:      Vec<Message>,
+    events:         VecDeque<Incoming>,     // Connection events, received before messages
+    connected:      bool,
+    drop_after:     Option<usize>,          // Lose the connection once this many messages were published
+    retained:       HashMap<String, Vec<u8>>,
+}
+
+impl Backoff {
+    /// Constructor
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            next: initial
+        }
+    }
+
+    /// next_delay()
+    ///
+    /// Returns how long to wait before the next attempt, doubling the one after
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = std::cmp::min(self.next * 2, self.max);
+        delay
+    }
+
+    /// reset()
+    ///
+    /// Starts over from 'initial' once connected
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+impl MqttTransport {
+    /// connect()
+    ///
+    /// Connects to the broker in 'mqtt_options', reconnecting after 'backoff'
+    /// once the connection is lost. rumqtt's own reconnection is turned off.
+    pub fn connect(mqtt_options: MqttOptions, mut backoff: Backoff) -> Result<MqttTransport, io::Error> {
+        let mqtt_options = mqtt_options.set_reconnect_opts(ReconnectOptions::Never);
+        let (client, notifications) = start(&mqtt_options)?;
+        backoff.reset();
+        Ok(MqttTransport {
+            mqtt_options,
+            client,
+            notifications,
+            pending: VecDeque::new(),
+            connected: true,
+            backoff,
+            retry_at: Instant::now()
+        })
+    }
+
+    /// lost()
+    ///
+    /// Notes that the broker can't be reached and when to try again
+    fn lost(&mut self) -> Incoming {
+        self.connected = false;
+        self.retry_at = Instant::now() + self.backoff.next_delay();
+        Incoming::ConnectionLost
+    }
+
+    /// reconnect()
+    ///
+    /// Connects again once it is time to, waiting up to 'timeout' for it
+    fn reconnect(&mut self, timeout: Duration) -> Incoming {
+        let now = Instant::now();
+        if now < self.retry_at {
+            thread::sleep(std::cmp::min(timeout, self.retry_at - now));
+            if Instant::now() < self.retry_at {
+                return Incoming::Timeout;
+            }
+        }
+        match start(&self.mqtt_options) {
+            Ok((client, notifications)) => {
+                self.client = client;
+                self.notifications = notifications;
+                self.connected = true;
+                self.backoff.reset();
+                Incoming::Reconnected
+            },
+            Err(error) => {
+                let delay = self.backoff.next_delay();
+                warn!("Could not reconnect to MQTT, retrying in {:?}! {:?}", delay, error);
+                self.retry_at = Instant::now() + delay;
+                Incoming::Timeout
+            }
+        }
+    }
+
+    /// incoming()
+    ///
+    /// Converts a notification, keeping track of the connection
+    fn incoming(&mut self, notification: Notification) -> Incoming {
+        match notification {
+            Notification::Publish(publish) => Incoming::Publish(Message {
+                topic: publish.topic_name,
+                payload: publish.payload.to_vec()
+            }),
+            Notification::Disconnection => self.lost(),
+            notification => Incoming::Other(format!("{:?}", notification))
+        }
+    }
+}
+
+impl Transport for MqttTransport {
+    fn publish(&mut self, topic: &str, payload: Vec<u8>) -> Result<(), io::Error> {
+        self.client.publish(topic, QoS::AtLeastOnce, false, payload).map_err(|error| io::Error::other(format!("{:?}", error)))
+    }
+
+    fn publish_retained(&mut self, topic: &str, payload: Vec<u8>) -> Result<(), io::Error> {
+        self.client.publish(topic, QoS::AtLeastOnce, true, payload).map_err(|error| io::Error::other(format!("{:?}", error)))
+    }
+
+    fn subscribe(&mut self, topic: &str) -> Result<(), io::Error> {
+        self.client.subscribe(topic, QoS::AtLeastOnce).map_err(|error| io::Error::other(format!("{:?}", error)))
+    }
+
+    fn receive(&mut self, timeout: Duration) -> Incoming {
+        if let Some(incoming) = self.pending.pop_front() {
+            return incoming;
+        }
+        if !self.connected {
+            return self.reconnect(timeout);
+        }
+        match self.notifications.recv_timeout(timeout) {
+            Ok(notification) => self.incoming(notification),
+            Err(error) if error.is_timeout() => Incoming::Timeout,
+            Err(_) => self.lost()  // The connection ended without a notification
+        }
+    }
+
+    fn is_connected(&mut self) -> bool {
+        while self.connected {
+            let incoming = match self.notifications.try_recv() {
+                Ok(notification) => self.incoming(notification),
+                Err(error) if error.is_disconnected() => self.lost(),
+                Err(_) => break
+            };
+            self.pending.push_back(incoming);
+        }
+        self.connected
+    }
+}
+
+/// start()
+///
+/// Starts a rumqtt client, returning once it is connected
+fn start(mqtt_options: &MqttOptions) -> Result<(MqttClient, Receiver<Notification>), io::Error> {
+    MqttClient::start(mqtt_options.clone())
+        .map_err(|error| io::Error::new(io::ErrorKind::ConnectionRefused, format!("{:?}", error)))
+}
+
+impl MockTransport {
+    /// Constructor
+    ///
+    /// Creates both ends of an in-process broker
+    pub fn new() -> (MockTransport, MockBroker) {
+        let (sender, receiver) = mpsc::channel();
+        let shared = Arc::new(Mutex::new(MockShared{connected: true, ..Default::default()}));
+        let transport = MockTransport {
+            incoming: receiver,
+            shared: shared.clone()
+        };
+        let broker = MockBroker {
+            incoming: Arc::new(Mutex::new(Some(sender))),
+            shared
+        };
+        (transport, broker)
+    }
+}
+
+impl Transport for MockTransport {
+    fn publish(&mut self, topic: &str, payload: Vec<u8>) -> Result<(), io::Error> {
+        let mut shared = self.shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if shared.drop_after == Some(shared.published.len()) {
+            // The broker restarts, a clean session loses its subscriptions
+            shared.drop_after = None;
+            shared.connected = false;
+            shared.subscriptions.clear();
+            shared.events.extend(vec![Incoming::ConnectionLost, Incoming::Reconnected]);
+        }
+        if !shared.connected {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "Not connected to the broker"));
+        }
+        shared.published.push(Message{topic: topic.to_string(), payload});
+        Ok(())
+    }
+
+    fn publish_retained(&mut self, topic: &str, payload: Vec<u8>) -> Result<(), io::Error> {
+        let mut shared = self.shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !shared.connected {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "Not connected to the broker"));
+        }
+        shared.retained.insert(topic.to_string(), payload);
+        Ok(())
+    }
+
+    fn subscribe(&mut self, topic: &str) -> Result<(), io::Error> {
+        let mut shared = self.shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        shared.subscriptions.push(topic.to_string());
+        Ok(())
+    }
+
+    fn receive(&mut self, timeout: Duration) -> Incoming {
+        {
+            let mut shared = self.shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Some(event) = shared.events.pop_front() {
+                shared.connected = event != Incoming::ConnectionLost;
+                return event;
+            }
+        }
+        loop {
+            let message = match self.incoming.recv_timeout(timeout) {
+                Ok(message) => message,
+                Err(mpsc::RecvTimeoutError::Timeout) => return Incoming::Timeout,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Incoming::Disconnected
+            };
+
+            // A broker only delivers topics that were subscribed to
+            let shared = self.shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if shared.subscriptions.contains(&message.topic) {
+                return Incoming::Publish(message);
+            }
+        }
+    }
+
+    fn is_connected(&mut self) -> bool {
+        self.shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).connected
+    }
+}
+
+impl MockBroker {
+    /// publish()
+    ///
+    /// Publishes a payload to the transport, returning false if it is gone
+    pub fn publish(&self, topic: &str, payload: Vec<u8>) -> bool {
+        match self.incoming.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).as_ref() {
+            Some(sender) => sender.send(Message{topic: topic.to_string(), payload}).is_ok(),
+            None => false
+        }
+    }
+
+    /// disconnect()
+    ///
+    /// Disconnects the transport once it received what was already published
+    pub fn disconnect(&self) {
+        self.incoming.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+    }
+
+    /// drop_after()
+    ///
+    /// Makes the broker restart once 'published' messages were published.
+    /// The message being published then is lost, and the transport
+    /// receives a lost connection followed by a reconnection.
+    pub fn drop_after(&self, published: usize) {
+        self.shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).drop_after = Some(published);
+    }
+
+    /// retained()
+    ///
+    /// Returns the payload retained for a topic
+    pub fn retained(&self, topic: &str) -> Option<Vec<u8>> {
+        self.shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).retained.get(topic).cloned()
+    }
+
+    /// subscriptions()
+    ///
+    /// Returns the topics the transport subscribed to
+    pub fn subscriptions(&self) -> Vec<String> {
+        self.shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).subscriptions.clone()
+    }
+
+    /// published()
+    ///
+    /// Returns everything the transport published, in order
+    pub fn published(&self) -> Vec<Message> {
+        self.shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).published.clone()
+    }
+}
+
+#[cfg(test)]
+mod transport_tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        // Every failed attempt waits longer, up to the maximum
+        let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(60));
+        let delays: Vec<u64> = (0..6).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![5, 10, 20, 40, 60, 60]);
+
+        // Connecting starts over
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_mqtt_transport_backoff() {
+        // Nothing listens on a port that was just freed
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mqtt_options = MqttOptions::new("backoff", "127.0.0.1", port);
+        assert!(MqttTransport::connect(mqtt_options.clone(), Backoff::new(Duration::from_millis(10), Duration::from_secs(1))).is_err());
+
+        // Attempts to reconnect are spaced further and further apart
+        let (client, notifications) = MqttClient::start(mqtt_options.clone().set_reconnect_opts(ReconnectOptions::Always(3600))).unwrap();
+        let mut transport = MqttTransport {
+            mqtt_options,
+            client,
+            notifications,
+            pending: VecDeque::new(),
+            connected: false,
+            backoff: Backoff::new(Duration::from_millis(20), Duration::from_millis(80)),
+            retry_at: Instant::now()
+        };
+        let mut waits = Vec::new();
+        for _ in 0..4 {
+            let attempt = transport.retry_at;
+            while transport.retry_at == attempt {
+                assert_eq!(transport.receive(Duration::from_millis(5)), Incoming::Timeout);
+            }
+            waits.push(transport.retry_at - attempt);
+        }
+        assert!(waits[1] > waits[0] && waits[2] > waits[1], "{:?}", waits);
+        assert!(!transport.is_connected());
+    }
+}
@@ -0,0 +1,698 @@
+use std::io;
+use std::io::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use chrono::prelude::*;
+use log::{error, info};
+
+use crate::database::{self, Database, Entry, MpdRecordType};
+use crate::rollup::Rollup;
+use crate::storage::Appender;
+
+/// Flush once this many bytes are buffered for a table
+pub const DEFAULT_MAX_BUFFERED: usize = 16 * 1024;
+
+/// Flush buffered records at least this often
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Close an hour file as soon as a record of a later hour arrives
+pub const DEFAULT_LATENESS: Duration = Duration::from_secs(0);
+
+/// Writer
+///
+/// Buffers records in memory and appends them to their hour files in
+/// batches. The current hour file of every table is kept open until the
+/// writer rolls over to the next hour or is closed.
+///
+/// An hour file stays open for 'lateness' after its hour has passed (going by
+/// the newest record of the table), so late records are still buffered. Records
+/// arriving after that are appended straight to their historical hour file.
+///
+/// Tables in dedup mode drop records whose id and checksum are already in
+/// their hour file. The open hour of such a table keeps these in memory.
+///
+/// Rollups of a table are rebuilt once their source hours are closed. A
+/// late record makes its rollup stale, which is rebuilt by the flush after
+/// the flush interval.
+///
+/// Thresholds are only checked when records are written, so `flush()`
+/// (or `flush_if_due()` on a timer) must be called to write the tail
+/// of the buffer, e.g. during shutdown.
+#[derive(Debug)]
+pub struct Writer {
+    database:       Database,
+    max_buffered:   usize,
+    flush_interval: Duration,
+    lateness:       Duration,
+    shards:         HashMap<(String, DateTime<Utc>), OpenShard>,
+    newest:         HashMap<String, u32>,
+    dedup:          HashSet<String>,
+    stats:          HashMap<String, TableStats>,
+    last_ingest:    Option<DateTime<Utc>>,
+    rollups:        Vec<Rollup>,
+    stale:          HashMap<(usize, i64), Instant>, // (rollup, start of its shard) since a late record
+}
+
+/// TableStats
+///
+/// Counts of what happened to the records written to a table
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TableStats {
+    pub written:        u64,    // Records accepted
+    pub late:           u64,    // Records written straight to a closed hour file
+    pub deduplicated:   u64,    // Records dropped as duplicates
+}
+
+/// OpenShard
+///
+/// The hour file a table is currently writing to
+#[derive(Debug)]
+struct OpenShard {
+    hour:       DateTime<Utc>,
+    file:       Option<Box<dyn Appender>>,
+    buf:        Vec<u8>,
+    last_flush: Instant,
+    seen:       Option<HashSet<(u32, u32)>>,    // (id, checksum) of every record, in dedup mode
+}
+
+impl Writer {
+    /// Constructor
+    pub fn new(database: Database) -> Result<Writer, io::Error> {
+        database.check_writable()?;
+        Ok(Writer {
+            database,
+            max_buffered:   DEFAULT_MAX_BUFFERED,
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            lateness:       DEFAULT_LATENESS,
+            shards:         HashMap::new(),
+            newest:         HashMap::new(),
+            dedup:          HashSet::new(),
+            stats:          HashMap::new(),
+            last_ingest:    None,
+            rollups:        Vec::new(),
+            stale:          HashMap::new()
+        })
+    }
+
+    /// set_max_buffered()
+    ///
+    /// Sets how many bytes a table may buffer before it is flushed
+    pub fn set_max_buffered(&mut self, bytes: usize) {
+        self.max_buffered = bytes;
+    }
+
+    /// set_flush_interval()
+    ///
+    /// Sets how long records may stay buffered before they are flushed
+    pub fn set_flush_interval(&mut self, interval: Duration) {
+        self.flush_interval = interval;
+    }
+
+    /// flush_interval()
+    ///
+    /// Returns how long records may stay buffered before they are flushed
+    pub fn flush_interval(&self) -> Duration {
+        self.flush_interval
+    }
+
+    /// set_lateness()
+    ///
+    /// Sets how long an hour file stays open for late records after its hour has passed
+    pub fn set_lateness(&mut self, lateness: Duration) {
+        self.lateness = lateness;
+    }
+
+    /// set_dedup()
+    ///
+    /// Turns dedup mode on or off for a table
+    pub fn set_dedup(&mut self, table: &str, enabled: bool) {
+        if enabled {
+            self.dedup.insert(table.to_string());
+        } else {
+            self.dedup.remove(table);
+        }
+    }
+
+    /// add_rollup()
+    ///
+    /// Keeps a rollup up to date with the records written to its source
+    pub fn add_rollup(&mut self, rollup: Rollup) {
+        self.rollups.push(rollup);
+    }
+
+    /// stats()
+    ///
+    /// Returns the stats of a table
+    pub fn stats(&self, table: &str) -> TableStats {
+        self.stats.get(table).cloned().unwrap_or_default()
+    }
+
+    /// last_ingest()
+    ///
+    /// Returns when a record was last written, if ever
+    pub fn last_ingest(&self) -> Option<DateTime<Utc>> {
+        self.last_ingest
+    }
+
+    /// write()
+    ///
+    /// Buffers a record with the given id (timestamp) for its hour file.
+    /// Returns false if the record was dropped as a duplicate.
+    pub fn write(&mut self, id: u32, entry: Entry) -> Result<bool, io::Error> {
+        database::validate_table_name(&entry.table)?;
+        let table = entry.table;
+        let hour = hour_of(id);
+        let record = MpdRecordType::new(id, entry.data);
+        let checksum = record.checksum;
+        let serialized_data = database::serialize_struct(record)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Could not serialize record"))?;
+        let dedup = self.dedup.contains(&table);
+
+        // Track the newest record of the table
+        let previous = self.newest.get(&table).cloned();
+        let newest = self.newest.entry(table.clone()).or_insert(id);
+        if id > *newest {
+            *newest = id;
+        }
+        let newest = *newest;
+
+        // Too late for the buffer, append straight to the historical hour file
+        let key = (table.clone(), hour);
+        if !self.shards.contains_key(&key) && is_closed(&hour, newest, self.lateness) {
+            info!("Late record {:?} for {:?}, writing to {:?}", id, table, hour);
+            let mut shard = OpenShard::new(hour);
+            if dedup && read_seen(&self.database, &table, &hour)?.contains(&(id, checksum)) {
+                info!("Duplicate record {:?} for {:?}, dropping", id, table);
+                self.stats.entry(table.clone()).or_default().deduplicated += 1;
+                return Ok(false);
+            }
+            shard.buf = serialized_data;
+            flush_shard(&self.database, &table, &mut shard)?;
+            self.last_ingest = Some(Utc::now());
+            let stats = self.stats.entry(table.clone()).or_default();
+            stats.written += 1;
+            stats.late += 1;
+
+            // Rollups that already cover the hour are out of date
+            let closed_until = closed_until(newest, self.lateness);
+            for (index, rollup) in self.rollups.iter().enumerate().filter(|(_, rollup)| rollup.source == table) {
+                let start = rollup.span_of(hour.timestamp());
+                if start + rollup.span() <= closed_until {
+                    self.stale.entry((index, start)).or_insert_with(Instant::now);
+                }
+            }
+            return Ok(true);
+        }
+
+        if !self.shards.contains_key(&key) {
+            let mut shard = OpenShard::new(hour);
+            if dedup {
+                shard.seen = Some(read_seen(&self.database, &table, &hour)?);
+            }
+            self.shards.insert(key.clone(), shard);
+        }
+        let shard = self.shards.get_mut(&key).unwrap();
+
+        // Drop the record if its hour already has it
+        if dedup {
+            let seen = shard.seen.get_or_insert_with(HashSet::new);
+            if !seen.insert((id, checksum)) {
+                info!("Duplicate record {:?} for {:?}, dropping", id, table);
+                self.stats.entry(table.clone()).or_default().deduplicated += 1;
+                return Ok(false);
+            }
+        }
+        shard.buf.extend_from_slice(&serialized_data);
+        self.last_ingest = Some(Utc::now());
+        self.stats.entry(table.clone()).or_default().written += 1;
+
+        // Flush if a threshold was reached
+        if shard.buf.len() >= self.max_buffered || shard.last_flush.elapsed() >= self.flush_interval {
+            flush_shard(&self.database, &table, shard)?;
+        }
+
+        // Roll over, closing hour files of the table that can't receive records anymore
+        let lateness = self.lateness;
+        let closed: Vec<_> = self.shards.keys()
+            .filter(|(shard_table, hour)| *shard_table == table && is_closed(hour, newest, lateness))
+            .cloned()
+            .collect();
+        for key in closed {
+            let mut shard = self.shards.remove(&key).unwrap();
+            flush_shard(&self.database, &key.0, &mut shard)?;
+        }
+
+        // Roll up what the closed hours completed
+        if let Some(previous) = previous {
+            self.roll_up(&table, closed_until(previous, lateness), closed_until(newest, lateness))?;
+        }
+        Ok(true)
+    }
+
+    /// roll_up()
+    ///
+    /// Rebuilds the rollups of a table whose shards end between 'from' (excluded)
+    /// and 'to', the times up to which its hours were closed before and after a write
+    fn roll_up(&mut self, table: &str, from: i64, to: i64) -> Result<(), io::Error> {
+        if to <= std::cmp::max(from, 0) {
+            return Ok(());
+        }
+        for rollup in self.rollups.iter().filter(|rollup| rollup.source == table) {
+            // Skip to the shards of the source that exist
+            let mut start = rollup.span_of(from);
+            while start + rollup.span() <= to {
+                let shard = self.database.storage().next_shard(table, &database::get_datetime(start as u32), &database::get_datetime(to as u32 - 1))?;
+                match shard {
+                    Some(shard) => start = rollup.span_of(shard.timestamp()),
+                    None => break
+                }
+                if start + rollup.span() > to {
+                    break;
+                }
+                rollup.rebuild(&self.database, start)?;
+                start += rollup.span();
+            }
+        }
+        Ok(())
+    }
+
+    /// rebuild_stale()
+    ///
+    /// Rebuilds the rollups made stale by late records, either all of them
+    /// or only those that have waited for the flush interval
+    fn rebuild_stale(&mut self, all: bool) -> Result<(), io::Error> {
+        let flush_interval = self.flush_interval;
+        let due: Vec<(usize, i64)> = self.stale.iter()
+            .filter(|(_, since)| all || since.elapsed() >= flush_interval)
+            .map(|(key, _)| *key)
+            .collect();
+        for (index, start) in due {
+            self.rollups[index].rebuild(&self.database, start)?;
+            self.stale.remove(&(index, start));
+        }
+        Ok(())
+    }
+
+    /// backfill()
+    ///
+    /// Writes what is buffered, then rebuilds a rollup table between 'start_time'
+    /// and 'end_time' from the hour files of its source, leaving out the shards
+    /// whose hours aren't closed yet. Returns the buckets written.
+    pub fn backfill(&mut self, table: &str, start_time: u32, end_time: u32) -> Result<usize, io::Error> {
+        let rollup = self.rollups.iter().find(|rollup| rollup.table == table).cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{:?} is not a rollup table", table)))?;
+        self.flush()?;
+
+        // Stop before the first shard that isn't closed, going by what is stored
+        // if nothing was written to the source since the writer started
+        let mut end_time = i64::from(end_time);
+        let newest = match self.newest.get(&rollup.source) {
+            Some(newest) => Some(*newest),
+            None => read_newest(&self.database, &rollup.source)?
+        };
+        if let Some(newest) = newest {
+            end_time = std::cmp::min(end_time, rollup.span_of(closed_until(newest, self.lateness)) - 1);
+        }
+        if end_time < i64::from(start_time) {
+            return Ok(0);
+        }
+        let buckets = rollup.backfill(&self.database, start_time, end_time as u32)?;
+        let rollups = &self.rollups;
+        self.stale.retain(|(index, start), _| {
+            let rollup = &rollups[*index];
+            rollup.table != table || *start + rollup.span() <= i64::from(start_time) || *start > end_time
+        });
+        return Ok(buckets);
+    }
+
+    /// flush_if_due()
+    ///
+    /// Flushes every table whose records have been buffered longer than the flush interval
+    pub fn flush_if_due(&mut self) -> Result<(), io::Error> {
+        for ((table, _), shard) in self.shards.iter_mut() {
+            if !shard.buf.is_empty() && shard.last_flush.elapsed() >= self.flush_interval {
+                flush_shard(&self.database, table, shard)?;
+            }
+        }
+        self.rebuild_stale(false)
+    }
+
+    /// flush()
+    ///
+    /// Writes every buffered record to disk
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        for ((table, _), shard) in self.shards.iter_mut() {
+            flush_shard(&self.database, table, shard)?;
+        }
+        self.rebuild_stale(true)
+    }
+
+    /// close()
+    ///
+    /// Flushes every buffered record and closes all open hour files
+    pub fn close(&mut self) -> Result<(), io::Error> {
+        self.flush()?;
+        self.shards.clear();
+        Ok(())
+    }
+}
+
+impl OpenShard {
+    /// Constructor
+    fn new(hour: DateTime<Utc>) -> OpenShard {
+        OpenShard {
+            hour,
+            file:       None,
+            buf:        Vec::new(),
+            last_flush: Instant::now(),
+            seen:       None
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        if let Err(error) = self.flush() {
+            error!("Could not flush writer! {:?}", error);
+        }
+    }
+}
+
+/// flush_shard()
+///
+/// Appends the buffered records of a table to its hour file,
+/// opening the file on first use
+fn flush_shard(database: &Database, table: &str, shard: &mut OpenShard) -> Result<(), io::Error> {
+    shard.last_flush = Instant::now();
+    if shard.buf.is_empty() {
+        return Ok(());
+    }
+
+    // Only one writer per table at a time
+    let lock = database.table_lock(table);
+    let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    if shard.file.is_none() {
+        shard.file = Some(database.storage().appender(table, &shard.hour)?);
+    }
+    shard.file.as_mut().unwrap().write_all(&shard.buf)?;
+    info!("Flushed {:?} bytes to {:?}", shard.buf.len(), table);
+    shard.buf.clear();
+    Ok(())
+}
+
+/// read_seen()
+///
+/// Reads the (id, checksum) of every record already in an hour file
+fn read_seen(database: &Database, table: &str, hour: &DateTime<Utc>) -> Result<HashSet<(u32, u32)>, io::Error> {
+    let lock = database.table_lock(table);
+    let _guard = lock.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    match database.storage().read_shard(table, hour)? {
+        Some(buf) => return Ok(database::decode_file(&buf).0.iter().map(|(_, entry)| (entry.id, entry.checksum)).collect()),
+        None => return Ok(HashSet::new())
+    }
+}
+
+/// read_newest()
+///
+/// Reads the id of the newest record stored in a table, from its last hour file
+fn read_newest(database: &Database, table: &str) -> Result<Option<u32>, io::Error> {
+    let lock = database.table_lock(table);
+    let _guard = lock.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    let hour = match database.storage().list_shards(table)?.pop() {
+        Some(hour) => hour,
+        None => return Ok(None)
+    };
+    match database.storage().read_shard(table, &hour)? {
+        Some(buf) => return Ok(database::decode_file(&buf).0.iter().map(|(_, entry)| entry.id).max()),
+        None => return Ok(None)
+    }
+}
+
+/// is_closed()
+///
+/// Checks if an hour can't receive records anymore, given the newest record of its table
+fn is_closed(hour: &DateTime<Utc>, newest: u32, lateness: Duration) -> bool {
+    return hour.timestamp() + 3600 + lateness.as_secs() as i64 <= i64::from(newest);
+}
+
+/// closed_until()
+///
+/// Returns the time before which every hour of a table is closed, given its newest record
+fn closed_until(newest: u32, lateness: Duration) -> i64 {
+    let time = i64::from(newest) - lateness.as_secs() as i64;
+    return time - time.rem_euclid(3600);
+}
+
+/// hour_of()
+///
+/// Returns the start of the hour a record id (timestamp) falls in
+fn hour_of(id: u32) -> DateTime<Utc> {
+    let datetime = database::get_datetime(id);
+    return datetime.date().and_hms(datetime.hour(), 0, 0);
+}
+
+#[cfg(test)]
+mod writer_tests {
+    use super::*;
+    use crate::fixtures::{count, TempDatabase};
+    use crate::database::RawData;
+    use crate::query::Bucket;
+
+    #[test]
+    fn test_writer_flush() {
+        let database = TempDatabase::new();
+        let mut writer = Writer::new(database.clone()).unwrap();
+        writer.set_flush_interval(Duration::from_secs(3600));
+        assert_eq!(writer.last_ingest(), None);
+
+        // 2020-01-04 00:00 to 00:09
+        let before = Utc::now();
+        for i in 0..10 {
+            writer.write(1578096000 + i, Entry{table: "writer_flush".to_string(), data: database::new_buf().unwrap()}).unwrap();
+        }
+        assert!(writer.last_ingest().unwrap() >= before);
+        assert_eq!(count(&database, "writer_flush", 1578096000, 1578099600), 0);  // Still buffered
+
+        writer.flush().unwrap();
+        assert_eq!(count(&database, "writer_flush", 1578096000, 1578099600), 10);
+
+        writer.close().unwrap();
+    }
+
+    #[test]
+    fn test_writer_memory() {
+        let database = Database::in_memory();
+        let mut writer = Writer::new(database.clone()).unwrap();
+
+        // 2020-01-04 00:00 to 00:09
+        for i in 0..10 {
+            writer.write(1578096000 + i, Entry{table: "writer_memory".to_string(), data: database::new_buf().unwrap()}).unwrap();
+        }
+        writer.flush().unwrap();
+        assert_eq!(count(&database, "writer_memory", 1578096000, 1578099600), 10);
+        writer.close().unwrap();
+    }
+
+    #[test]
+    fn test_writer_thresholds() {
+        let database = TempDatabase::new();
+        let mut writer = Writer::new(database.clone()).unwrap();
+        writer.set_flush_interval(Duration::from_secs(3600));
+        writer.set_max_buffered(1);
+
+        // Every record fills the buffer
+        writer.write(1578096000, Entry{table: "writer_thresholds".to_string(), data: database::new_buf().unwrap()}).unwrap();
+        assert_eq!(count(&database, "writer_thresholds", 1578096000, 1578099600), 1);
+
+        // Every record is older than the flush interval
+        writer.set_max_buffered(DEFAULT_MAX_BUFFERED);
+        writer.set_flush_interval(Duration::from_secs(0));
+        writer.write(1578096001, Entry{table: "writer_thresholds".to_string(), data: database::new_buf().unwrap()}).unwrap();
+        assert_eq!(count(&database, "writer_thresholds", 1578096000, 1578099600), 2);
+
+        writer.close().unwrap();
+    }
+
+    #[test]
+    fn test_writer_roll() {
+        let database = TempDatabase::new();
+        let mut writer = Writer::new(database.clone()).unwrap();
+        writer.set_flush_interval(Duration::from_secs(3600));
+
+        // 2020-01-04 23:59:59 then 2020-01-05 00:00:00
+        writer.write(1578182399, Entry{table: "writer_roll".to_string(), data: database::new_buf().unwrap()}).unwrap();
+        writer.write(1578182400, Entry{table: "writer_roll".to_string(), data: database::new_buf().unwrap()}).unwrap();
+
+        // Rolling flushed the previous hour only
+        assert_eq!(count(&database, "writer_roll", 1578178800, 1578182399), 1);
+        assert_eq!(count(&database, "writer_roll", 1578182400, 1578186000), 0);
+        drop(writer);
+        assert_eq!(count(&database, "writer_roll", 1578182400, 1578186000), 1);
+    }
+
+    #[test]
+    fn test_writer_out_of_order() {
+        let database = TempDatabase::new();
+        let mut writer = Writer::new(database.clone()).unwrap();
+
+        // 2020-01-06 00:30, 00:10, 00:20
+        for id in [1578270600, 1578269400, 1578270000].iter() {
+            writer.write(*id, Entry{table: "writer_order".to_string(), data: database::new_buf().unwrap()}).unwrap();
+        }
+        writer.close().unwrap();
+
+        // The later record written first doesn't hide the others
+        let mut cursor = database.get_data("writer_order", 1578268800, 1578270300).unwrap();
+        let mut record: Option<MpdRecordType> = None;
+        let mut ids = Vec::new();
+        loop {
+            cursor.next(&mut record);
+            match record.take() {
+                Some(entry) => ids.push(entry.id),
+                None => break
+            }
+        }
+        assert_eq!(ids, vec![1578269400, 1578270000]);
+    }
+
+    #[test]
+    fn test_writer_lateness() {
+        let database = TempDatabase::new();
+        let mut writer = Writer::new(database.clone()).unwrap();
+        writer.set_flush_interval(Duration::from_secs(3600));
+        writer.set_lateness(Duration::from_secs(60));
+
+        // 2020-01-06 00:59:30, then 01:00:30 which is within the window
+        writer.write(1578272370, Entry{table: "writer_late".to_string(), data: database::new_buf().unwrap()}).unwrap();
+        writer.write(1578272430, Entry{table: "writer_late".to_string(), data: database::new_buf().unwrap()}).unwrap();
+        assert_eq!(count(&database, "writer_late", 1578268800, 1578272399), 0);  // Hour 00 is still open
+
+        // Late record still buffered for hour 00
+        writer.write(1578272390, Entry{table: "writer_late".to_string(), data: database::new_buf().unwrap()}).unwrap();
+        assert_eq!(count(&database, "writer_late", 1578268800, 1578272399), 0);
+
+        // 01:02:00 closes hour 00
+        writer.write(1578272520, Entry{table: "writer_late".to_string(), data: database::new_buf().unwrap()}).unwrap();
+        assert_eq!(count(&database, "writer_late", 1578268800, 1578272399), 2);
+
+        // Beyond the window, written straight to the hour 00 file
+        writer.write(1578272395, Entry{table: "writer_late".to_string(), data: database::new_buf().unwrap()}).unwrap();
+        assert_eq!(count(&database, "writer_late", 1578268800, 1578272399), 3);
+
+        writer.close().unwrap();
+        assert_eq!(count(&database, "writer_late", 1578272400, 1578276000), 2);
+    }
+
+    #[test]
+    fn test_writer_dedup() {
+        let database = TempDatabase::new();
+        let mut writer = Writer::new(database.clone()).unwrap();
+        writer.set_dedup("writer_dedup", true);
+
+        // 2020-01-07 00:00, the same reading published twice
+        let buf: Vec<u8> = database::new_buf().unwrap();
+        assert!(writer.write(1578355200, Entry{table: "writer_dedup".to_string(), data: buf.clone()}).unwrap());
+        let last_ingest = writer.last_ingest();
+        assert!(!writer.write(1578355200, Entry{table: "writer_dedup".to_string(), data: buf.clone()}).unwrap());
+        assert_eq!(writer.last_ingest(), last_ingest);  // Duplicates aren't ingested
+
+        // Same id with different data is kept
+        assert!(writer.write(1578355200, Entry{table: "writer_dedup".to_string(), data: database::new_buf().unwrap()}).unwrap());
+        writer.close().unwrap();
+        assert_eq!(count(&database, "writer_dedup", 1578355200, 1578358800), 2);
+
+        // A new writer checks what is already on disk
+        let mut writer = Writer::new(database.clone()).unwrap();
+        writer.set_dedup("writer_dedup", true);
+        assert!(!writer.write(1578355200, Entry{table: "writer_dedup".to_string(), data: buf.clone()}).unwrap());
+
+        // Including for hours that were already closed
+        writer.write(1578362400, Entry{table: "writer_dedup".to_string(), data: database::new_buf().unwrap()}).unwrap();
+        assert!(!writer.write(1578355200, Entry{table: "writer_dedup".to_string(), data: buf}).unwrap());
+        assert_eq!(writer.stats("writer_dedup"), TableStats{written: 1, late: 0, deduplicated: 2});
+        writer.close().unwrap();
+        assert_eq!(count(&database, "writer_dedup", 1578355200, 1578358800), 2);
+    }
+
+    /// rolled_up()
+    ///
+    /// The (start, records) of every bucket stored in a rollup table
+    fn rolled_up(database: &Database, table: &str) -> Vec<(u32, u64)> {
+        let mut cursor = database.get_data(table, 0, u32::MAX).unwrap();
+        let mut buckets = Vec::new();
+        while let Some(record) = cursor.try_next().unwrap() {
+            let bucket = Bucket::from_record(&record).unwrap();
+            buckets.push((bucket.start, bucket.records));
+        }
+        return buckets;
+    }
+
+    #[test]
+    fn test_writer_rollup() {
+        let database = Database::in_memory();
+        let mut writer = Writer::new(database.clone()).unwrap();
+        writer.set_flush_interval(Duration::from_secs(3600));
+        writer.add_rollup(Rollup::new("writer_rollup_1h", "writer_rollup", 3600, Vec::new()).unwrap());
+        writer.add_rollup(Rollup::new("writer_rollup_1d", "writer_rollup", 86400, Vec::new()).unwrap());
+        let mut write = |id: u32| {
+            let data = rmps::to_vec(&RawData{NO2: Some(1.0), ..Default::default()}).unwrap();
+            writer.write(id, Entry{table: "writer_rollup".to_string(), data}).unwrap();
+        };
+
+        // 2020-01-01 22:00, 22:30, then 23:10 closes hour 22
+        write(1577916000);
+        write(1577917800);
+        assert!(rolled_up(&database, "writer_rollup_1h").is_empty());
+        write(1577920200);
+        assert_eq!(rolled_up(&database, "writer_rollup_1h"), vec![(1577916000, 2)]);
+        assert!(rolled_up(&database, "writer_rollup_1d").is_empty());
+
+        // 2020-01-02 00:05 closes hour 23 and the day
+        write(1577923500);
+        assert_eq!(rolled_up(&database, "writer_rollup_1h"), vec![(1577916000, 2), (1577919600, 1)]);
+        assert_eq!(rolled_up(&database, "writer_rollup_1d"), vec![(1577836800, 3)]);
+
+        // A late record at 22:45 is rolled up by the next flush
+        write(1577918700);
+        assert_eq!(rolled_up(&database, "writer_rollup_1d"), vec![(1577836800, 3)]);
+        writer.flush().unwrap();
+        assert_eq!(rolled_up(&database, "writer_rollup_1h"), vec![(1577916000, 3), (1577919600, 1)]);
+        assert_eq!(rolled_up(&database, "writer_rollup_1d"), vec![(1577836800, 4)]);
+
+        // 2020-01-03 05:00 closes the 2nd even though its last hours have no records
+        writer.write(1578027600, Entry{table: "writer_rollup".to_string(), data: database::new_buf().unwrap()}).unwrap();
+        assert_eq!(rolled_up(&database, "writer_rollup_1d"), vec![(1577836800, 4), (1577923200, 1)]);
+
+        // Backfilling leaves out the open day
+        database.delete_file("writer_rollup_1d", "20200101/00").unwrap();
+        assert_eq!(writer.backfill("writer_rollup_1d", 0, u32::MAX).unwrap(), 2);
+        assert_eq!(rolled_up(&database, "writer_rollup_1d"), vec![(1577836800, 4), (1577923200, 1)]);
+        assert_eq!(writer.backfill("writer_rollup", 0, u32::MAX).unwrap_err().kind(), io::ErrorKind::NotFound);
+        writer.close().unwrap();
+        assert_eq!(count(&database, "writer_rollup_1h", 0, u32::MAX), 3);
+    }
+
+    #[test]
+    fn test_writer_backfill_after_restart() {
+        let database = Database::in_memory();
+        let mut writer = Writer::new(database.clone()).unwrap();
+        writer.set_lateness(Duration::from_secs(60));
+        writer.add_rollup(Rollup::new("writer_restart_1h", "writer_restart", 3600, Vec::new()).unwrap());
+        let store = |id: u32| database.insert_record("writer_restart", MpdRecordType::new(id, database::new_buf().unwrap())).unwrap();
+
+        // 2020-01-01 22:00, 23:00 and 23:59 were stored before the writer started, hour 23 is still open
+        for id in [1577916000, 1577919600, 1577923140].iter() {
+            store(*id);
+        }
+        assert_eq!(writer.backfill("writer_restart_1h", 0, u32::MAX).unwrap(), 1);
+        assert_eq!(rolled_up(&database, "writer_restart_1h"), vec![(1577916000, 1)]);
+
+        // 2020-01-02 00:01 is past the lateness window of hour 23
+        store(1577923260);
+        assert_eq!(writer.backfill("writer_restart_1h", 0, u32::MAX).unwrap(), 2);
+        assert_eq!(rolled_up(&database, "writer_restart_1h"), vec![(1577916000, 1), (1577919600, 2)]);
+    }
+}
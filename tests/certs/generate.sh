@@ -0,0 +1,26 @@
+#!/bin/sh
+# Regenerates the certificates used by the TLS tests.
+# rustls 0.15 (through rumqtt) needs RSA keys in PKCS#1 PEM and DNS names in the certificates.
+set -e
+cd "$(dirname "$0")"
+
+cat > server.ext <<END
+basicConstraints = critical, CA:FALSE
+keyUsage = critical, digitalSignature, keyEncipherment
+extendedKeyUsage = serverAuth
+subjectAltName = DNS:localhost
+END
+cat > client.ext <<END
+basicConstraints = critical, CA:FALSE
+keyUsage = critical, digitalSignature, keyEncipherment
+extendedKeyUsage = clientAuth
+END
+
+openssl req -x509 -newkey rsa:2048 -nodes -sha256 -days 36500 -subj "/CN=LocalStorage Test CA" -keyout ca.key -out ca.pem -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign"
+for name in server client; do
+    openssl req -newkey rsa:2048 -nodes -sha256 -subj "/CN=localhost" -keyout $name.key.pkcs8 -out $name.csr
+    openssl x509 -req -sha256 -days 36500 -in $name.csr -CA ca.pem -CAkey ca.key -CAcreateserial -extfile $name.ext -out $name.pem
+    openssl rsa -traditional -in $name.key.pkcs8 -out $name.key
+    rm $name.key.pkcs8 $name.csr
+done
+rm -f server.ext client.ext ca.srl ca.key